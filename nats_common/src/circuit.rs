@@ -0,0 +1,143 @@
+// circuit.rs
+// Circuit breaker state of one Lambda function, kept in the topology's circuit KV bucket
+// that nats_consumer updates and nats_web shows and resets.
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CircuitState {
+    /// Invocations run; failures are counted
+    #[default]
+    Closed,
+    /// Too many consecutive failures: events for the function are parked in the holding stream
+    Open,
+    /// The cooldown passed and one probe invocation decides whether the circuit closes
+    HalfOpen,
+}
+
+/// What to do with an invocation of the function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    Allow,
+    /// The cooldown passed: the invocation may run as the probe
+    Probe,
+    /// Park the event; a probe may run after the returned time
+    Hold(TimeDelta),
+}
+
+/// Stored as JSON under `circuit_key(lambda_arn)`. A function without a record is closed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Circuit {
+    pub lambda_arn: String,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub last_failure: Option<String>,
+    #[serde(default)]
+    pub opened_at: Option<String>,   // RFC 3339
+    #[serde(default)]
+    pub probe_at: Option<String>,    // RFC 3339. Open: when the probe may run; HalfOpen: when another probe may run if this one never reports
+}
+
+impl Circuit {
+    pub fn new(lambda_arn: &str) -> Self {
+        Circuit {
+            lambda_arn: lambda_arn.to_string(),
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            last_failure: None,
+            opened_at: None,
+            probe_at: None,
+        }
+    }
+
+    pub fn admission(&self, now: DateTime<Utc>) -> Admission {
+        let probe_at = self
+            .probe_at
+            .as_deref()
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+            .map(|at| at.with_timezone(&Utc));
+        match (self.state, probe_at) {
+            (CircuitState::Closed, _) | (_, None) => Admission::Allow,
+            (_, Some(probe_at)) if now >= probe_at => Admission::Probe,
+            (_, Some(probe_at)) => Admission::Hold(probe_at - now),
+        }
+    }
+
+    /// Counts a failed invocation, opening the circuit once `threshold` failures in a row
+    /// were seen, or right away when the probe failed. Returns whether the circuit opened.
+    pub fn record_failure(&mut self, reason: &str, now: DateTime<Utc>, threshold: u32, cooldown: TimeDelta) -> bool {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.last_failure = Some(reason.to_string());
+        let opens = match self.state {
+            CircuitState::Closed => self.consecutive_failures >= threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if opens {
+            self.state = CircuitState::Open;
+            self.opened_at = Some(now.to_rfc3339());
+            self.probe_at = Some((now + cooldown).to_rfc3339());
+        }
+        opens
+    }
+
+    /// Lets one invocation through as the probe. Another probe may run after `cooldown`
+    /// if this one never reports back.
+    pub fn start_probe(&mut self, now: DateTime<Utc>, cooldown: TimeDelta) {
+        self.state = CircuitState::HalfOpen;
+        self.probe_at = Some((now + cooldown).to_rfc3339());
+    }
+}
+
+/// KV key of the function's circuit. ARNs contain ':' and may contain '$', which KV keys
+/// and subject tokens do not allow, so the ARN is hex encoded.
+pub fn circuit_key(lambda_arn: &str) -> String {
+    lambda_arn.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARN: &str = "arn:aws:lambda:us-east-1:123456789012:function:orders:$LATEST";
+
+    #[test]
+    fn opens_after_consecutive_failures_and_half_opens_after_the_cooldown() {
+        let now = Utc::now();
+        let cooldown = TimeDelta::seconds(60);
+        let mut circuit = Circuit::new(ARN);
+        assert!(!circuit.record_failure("boom", now, 3, cooldown));
+        assert!(!circuit.record_failure("boom", now, 3, cooldown));
+        assert_eq!(circuit.admission(now), Admission::Allow);
+
+        assert!(circuit.record_failure("boom", now, 3, cooldown));
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert_eq!(circuit.admission(now + TimeDelta::seconds(20)), Admission::Hold(TimeDelta::seconds(40)));
+        assert_eq!(circuit.admission(now + cooldown), Admission::Probe);
+
+        // Only one probe until the cooldown passes again
+        circuit.start_probe(now + cooldown, cooldown);
+        assert_eq!(circuit.admission(now + cooldown), Admission::Hold(cooldown));
+    }
+
+    #[test]
+    fn failed_probe_opens_the_circuit_again() {
+        let now = Utc::now();
+        let cooldown = TimeDelta::seconds(60);
+        let mut circuit = Circuit::new(ARN);
+        circuit.start_probe(now, cooldown);
+
+        let later = now + TimeDelta::seconds(5);
+        assert!(circuit.record_failure("still broken", later, 10, cooldown));
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert_eq!(circuit.admission(later + cooldown), Admission::Probe);
+    }
+
+    #[test]
+    fn keys_are_valid_kv_keys_and_subject_tokens() {
+        let key = circuit_key(ARN);
+        assert!(key.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(key, circuit_key("arn:aws:lambda:us-east-1:123456789012:function:billing"));
+    }
+}
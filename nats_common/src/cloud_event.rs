@@ -0,0 +1,168 @@
+// cloud_event.rs
+// CloudEvents 1.0 events on the event subject. They arrive in structured mode, a JSON
+// object with `specversion`, `id`, `source`, `type` and `data`, or in binary mode, with the
+// attributes in `ce-*` headers and the data as the message body. Rules can also deliver
+// events to their targets as structured CloudEvents.
+use base64ct::{Base64, Encoding};
+use serde_json::{Map, Value};
+
+pub const SPEC_VERSION: &str = "1.0";
+/// Prefix of the NATS headers holding the attributes of a binary-mode event.
+pub const HEADER_PREFIX: &str = "ce-";
+const CONTENT_TYPE_HEADER: &str = "content-type";
+const JSON_CONTENT_TYPE: &str = "application/json";
+// Source of events converted to CloudEvents that had no envelope `source`
+const DEFAULT_SOURCE: &str = "nats_consumer";
+const REQUIRED_ATTRIBUTES: [&str; 4] = ["specversion", "id", "source", "type"];
+
+/// Whether the event is a structured-mode CloudEvent.
+pub fn is_cloud_event(event: &Value) -> bool {
+    event.get("specversion").is_some_and(Value::is_string)
+}
+
+/// The type rules route the event on: its `event_type`, or the `type` of a CloudEvent.
+pub fn event_type(event: &Value) -> Option<&str> {
+    match event.get("event_type").and_then(Value::as_str) {
+        Some(event_type) => Some(event_type),
+        None if is_cloud_event(event) => event.get("type").and_then(Value::as_str),
+        None => None,
+    }
+}
+
+/// Checks that a structured-mode event is a CloudEvent this version understands.
+pub fn validate(event: &Value) -> Result<(), String> {
+    let version = event["specversion"].as_str().unwrap_or_default();
+    if version.split('.').next() != Some("1") {
+        return Err(format!("CloudEvents specversion {} is not supported", version));
+    }
+    for attribute in REQUIRED_ATTRIBUTES {
+        if event.get(attribute).and_then(Value::as_str).is_none_or(str::is_empty) {
+            return Err(format!("CloudEvent has no {} attribute", attribute));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the headers, given as (name, value) pairs, carry a binary-mode CloudEvent.
+pub fn is_binary<'a>(mut headers: impl Iterator<Item = (&'a str, &'a str)>) -> bool {
+    headers.any(|(name, _)| name.eq_ignore_ascii_case("ce-specversion"))
+}
+
+/// Structured form of a binary-mode event. JSON data stays JSON, other text becomes a
+/// string and anything else goes to `data_base64`.
+pub fn from_binary<'a>(headers: impl Iterator<Item = (&'a str, &'a str)>, body: &[u8]) -> Result<Value, String> {
+    let mut event = Map::new();
+    for (name, value) in headers {
+        let name = name.to_ascii_lowercase();
+        if let Some(attribute) = name.strip_prefix(HEADER_PREFIX) {
+            event.insert(attribute.to_string(), value.into());
+        } else if name == CONTENT_TYPE_HEADER {
+            event.insert("datacontenttype".to_string(), value.into());
+        }
+    }
+
+    if !body.is_empty() {
+        let is_json = event
+            .get("datacontenttype")
+            .and_then(Value::as_str)
+            .is_none_or(|content_type| content_type.contains("json"));
+        let json = if is_json { serde_json::from_slice(body).ok() } else { None };
+        match (json, std::str::from_utf8(body)) {
+            (Some(data), _) => event.insert("data".to_string(), data),
+            (None, Ok(text)) => event.insert("data".to_string(), text.into()),
+            (None, Err(_)) => event.insert("data_base64".to_string(), Base64::encode_string(body).into()),
+        };
+    }
+
+    let event = Value::Object(event);
+    validate(&event)?;
+    Ok(event)
+}
+
+/// The event as a structured-mode CloudEvent carrying `data`, or the event's own data when
+/// `data` is None. A CloudEvent keeps its attributes; any other event gets `id`, and its
+/// envelope `source` and `time` if it has them, and its event type or `default_type` as `type`.
+pub fn to_cloud_event(event: &Value, data: Option<Value>, id: &str, default_type: &str) -> Value {
+    if is_cloud_event(event) {
+        let mut cloud_event = event.clone();
+        if let (Some(data), Value::Object(fields)) = (data, &mut cloud_event) {
+            fields.remove("data_base64");
+            fields.insert("data".to_string(), data);
+            fields.insert("datacontenttype".to_string(), JSON_CONTENT_TYPE.into());
+        }
+        return cloud_event;
+    }
+
+    let text = |name: &str| event.get(name).and_then(Value::as_str);
+    let mut cloud_event = Map::new();
+    cloud_event.insert("specversion".to_string(), SPEC_VERSION.into());
+    cloud_event.insert("id".to_string(), id.into());
+    cloud_event.insert("source".to_string(), text("source").unwrap_or(DEFAULT_SOURCE).into());
+    let event_type = event_type(event).or_else(|| text("detail-type")).unwrap_or(default_type);
+    cloud_event.insert("type".to_string(), event_type.into());
+    if let Some(time) = text("time") {
+        cloud_event.insert("time".to_string(), time.into());
+    }
+    cloud_event.insert("datacontenttype".to_string(), JSON_CONTENT_TYPE.into());
+    cloud_event.insert("data".to_string(), data.unwrap_or_else(|| event.clone()));
+    Value::Object(cloud_event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn binary_mode_headers_become_attributes() {
+        let headers = [
+            ("ce-specversion", "1.0"),
+            ("ce-id", "A234-1234-1234"),
+            ("Ce-Source", "/orders"),
+            ("ce-type", "com.example.order.created"),
+            ("Content-Type", "application/json"),
+            ("Nats-Msg-Id", "A234-1234-1234"),
+        ];
+        assert!(is_binary(headers.into_iter()));
+
+        let event = from_binary(headers.into_iter(), br#"{"order_id": 7}"#).unwrap();
+        assert_eq!(
+            event,
+            json!({
+                "specversion": "1.0",
+                "id": "A234-1234-1234",
+                "source": "/orders",
+                "type": "com.example.order.created",
+                "datacontenttype": "application/json",
+                "data": {"order_id": 7},
+            })
+        );
+        assert_eq!(event_type(&event), Some("com.example.order.created"));
+
+        let binary = from_binary(headers[..4].iter().copied().chain([("content-type", "image/png")]), &[0xff, 0x00]).unwrap();
+        assert_eq!(binary["data_base64"], "/wA=");
+        assert!(from_binary([("ce-specversion", "1.0"), ("ce-id", "1")].into_iter(), b"").is_err());
+    }
+
+    #[test]
+    fn routes_on_type_only_for_cloud_events() {
+        assert_eq!(event_type(&json!({"event_type": "order.created"})), Some("order.created"));
+        assert_eq!(event_type(&json!({"type": "order.created"})), None);
+        assert!(validate(&json!({"specversion": "0.3", "id": "1", "source": "/", "type": "t"})).is_err());
+    }
+
+    #[test]
+    fn converts_events_for_delivery() {
+        let event = json!({"event_type": "order.created", "source": "orders-service", "order_id": 7});
+        let cloud_event = to_cloud_event(&event, None, "0190a8f2", "fallback");
+        assert_eq!(cloud_event["id"], "0190a8f2");
+        assert_eq!(cloud_event["source"], "orders-service");
+        assert_eq!(cloud_event["type"], "order.created");
+        assert_eq!(cloud_event["data"], event);
+        assert!(validate(&cloud_event).is_ok());
+
+        let incoming = json!({"specversion": "1.0", "id": "1", "source": "/orders", "type": "t", "data": {"order_id": 7}});
+        assert_eq!(to_cloud_event(&incoming, None, "other", "fallback"), incoming);
+        assert_eq!(to_cloud_event(&incoming, Some(json!(7)), "other", "fallback")["data"], 7);
+    }
+}
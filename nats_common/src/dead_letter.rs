@@ -0,0 +1,83 @@
+// dead_letter.rs
+// Records for events that exhausted their retries, kept in the topology's dead-letter
+// stream that nats_consumer writes and nats_web browses.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Fields the trigger and status checker add to an event while tracking it
+const TRACKING_FIELDS: [&str; 9] = [
+    "retry_index",
+    "event_id",
+    "rule",
+    "target_id",
+    "lambda_arn",
+    "lambda_request_id",
+    "attempts",
+    "result_subject",
+    "retry_policy",
+];
+
+/// One invocation of a target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attempt {
+    pub retry_index: u64,
+    pub request_id: String,
+    pub invoked_at: String,          // RFC 3339
+    #[serde(default)]
+    pub failure_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub event_id: String,
+    pub rule: String,
+    pub target_id: String,
+    pub lambda_arn: String,
+    pub payload: Value,              // The event as originally published, without tracking fields
+    pub attempts: Vec<Attempt>,
+    pub failure_reason: String,      // Why the last attempt failed
+    pub dead_lettered_at: String,    // RFC 3339
+}
+
+impl DeadLetter {
+    /// Builds the record from a status check payload whose last attempt failed.
+    pub fn from_status_payload(status: &Value, failure_reason: String, dead_lettered_at: String) -> Self {
+        let field = |name: &str| status.get(name).and_then(Value::as_str).unwrap_or_default().to_string();
+        DeadLetter {
+            event_id: field("event_id"),
+            rule: field("rule"),
+            target_id: field("target_id"),
+            lambda_arn: field("lambda_arn"),
+            payload: original_payload(status),
+            attempts: status
+                .get("attempts")
+                .and_then(|a| serde_json::from_value(a.clone()).ok())
+                .unwrap_or_default(),
+            failure_reason,
+            dead_lettered_at,
+        }
+    }
+
+    /// Event to publish to the event subject to redrive the record. It starts a fresh
+    /// retry chain for the failed target only, or goes to every matching rule again
+    /// for an event that failed before it reached a target.
+    pub fn redrive_event(&self) -> Value {
+        let mut event = self.payload.clone();
+        if event.is_object() && !self.rule.is_empty() {
+            event["rule"] = self.rule.clone().into();
+            event["target_id"] = self.target_id.clone().into();
+        }
+        event
+    }
+}
+
+/// The event without the fields added while it was tracked.
+pub fn original_payload(status: &Value) -> Value {
+    let mut payload = status.clone();
+    if let Value::Object(fields) = &mut payload {
+        for name in TRACKING_FIELDS {
+            fields.remove(name);
+        }
+    }
+    payload
+}
@@ -0,0 +1,104 @@
+// envelope.rs
+// Envelope of an event published to the event subject: top-level `id`, `source`, `time`
+// and `detail-type` fields, as in an EventBridge event. The rest of the event is free-form.
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Header JetStream deduplicates messages on within the stream's duplicate window.
+/// Producers set it to the envelope `id`.
+pub const MSG_ID_HEADER: &str = "Nats-Msg-Id";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub id: String,                  // UUID; the same event published twice keeps its id
+    pub source: String,              // What published the event, e.g. "orders-service"
+    pub time: String,                // RFC 3339
+    #[serde(rename = "detail-type")]
+    pub detail_type: String,         // What happened, e.g. "order.created"
+}
+
+impl Envelope {
+    /// Envelope of a new event, published now.
+    pub fn new(source: &str, detail_type: &str) -> Self {
+        Envelope {
+            id: new_id(),
+            source: source.to_string(),
+            time: Utc::now().to_rfc3339(),
+            detail_type: detail_type.to_string(),
+        }
+    }
+
+    /// Sets the envelope fields of an event object.
+    pub fn apply(&self, event: &mut Value) {
+        if let Value::Object(fields) = event {
+            fields.insert("id".to_string(), self.id.clone().into());
+            fields.insert("source".to_string(), self.source.clone().into());
+            fields.insert("time".to_string(), self.time.clone().into());
+            fields.insert("detail-type".to_string(), self.detail_type.clone().into());
+        }
+    }
+}
+
+/// A new time-ordered UUIDv7 event id.
+pub fn new_id() -> String {
+    Uuid::now_v7().to_string()
+}
+
+/// The envelope `id` of an event, if it has one.
+pub fn event_id(event: &Value) -> Option<&str> {
+    event.get("id").and_then(Value::as_str).filter(|id| !id.is_empty())
+}
+
+/// UUIDv5 id derived from `name`, for an event that has to get the same id every time
+/// it is published, e.g. one run of a schedule.
+pub fn derived_id(name: &str) -> String {
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes()).to_string()
+}
+
+/// Message id of the `attempt`th retry of an event for one target: distinct per retry,
+/// so deduplication drops a duplicate publish but never the next retry, and traceable
+/// to the event by its prefix.
+pub fn retry_msg_id(event_id: &str, target_id: &str, attempt: u64) -> String {
+    format!("{}.{}.{}", event_id, target_id, attempt)
+}
+
+/// Message id of a republish of an event for one target that is not a retry, e.g. its
+/// release from the holding stream. `kind` tells it apart from the event's retries and other
+/// republishes, and stays the same when that republish is attempted again.
+pub fn republish_msg_id(event_id: &str, target_id: &str, kind: &str) -> String {
+    format!("{}.{}.{}", event_id, target_id, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn applies_to_an_event_and_keeps_its_other_fields() {
+        let envelope = Envelope::new("orders-service", "order.created");
+        let mut event = json!({"event_type": "order.created", "detail": {"order_id": 7}});
+        envelope.apply(&mut event);
+
+        assert_eq!(event_id(&event), Some(envelope.id.as_str()));
+        assert_eq!(event["detail-type"], "order.created");
+        assert_eq!(event["detail"]["order_id"], 7);
+        assert_eq!(serde_json::from_value::<Envelope>(event).unwrap(), envelope);
+        assert_ne!(Envelope::new("orders-service", "order.created").id, envelope.id);
+    }
+
+    #[test]
+    fn derived_ids_are_stable_and_retry_ids_distinct() {
+        assert_eq!(derived_id("nightly@2025-01-01T00:00:00+00:00"), derived_id("nightly@2025-01-01T00:00:00+00:00"));
+        assert_ne!(derived_id("nightly@2025-01-01T00:00:00+00:00"), derived_id("nightly@2025-01-02T00:00:00+00:00"));
+
+        let id = derived_id("event");
+        assert_ne!(retry_msg_id(&id, "1", 1), retry_msg_id(&id, "1", 2));
+        assert_ne!(retry_msg_id(&id, "1", 1), retry_msg_id(&id, "2", 1));
+        assert!(retry_msg_id(&id, "1", 1).starts_with(&id));
+        assert_ne!(republish_msg_id(&id, "1", "release-7"), retry_msg_id(&id, "1", 7));
+        assert_ne!(republish_msg_id(&id, "1", "release-7"), republish_msg_id(&id, "1", "release-8"));
+    }
+}
@@ -0,0 +1,316 @@
+// expression.rs
+use chrono::{DateTime, LocalResult, NaiveDateTime, Offset, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+const AT_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+const MAX_SEARCH_STEPS: usize = 1000;
+
+/// What to do with a run whose wall-clock time falls into a DST gap (spring-forward).
+/// Runs in a DST overlap (fall-back) always fire once, on the first occurrence.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DstGapPolicy {
+    /// Run after the gap, shifted by the gap length (02:30 becomes 03:30)
+    #[default]
+    Shift,
+    /// Drop the run for that day
+    Skip,
+}
+
+/// A parsed schedule expression: a cron string, or the EventBridge Scheduler
+/// `rate(...)` and `at(...)` forms.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleExpression {
+    /// Cron expression, e.g. `0 9 * * *`
+    Cron(Box<Cron>),
+    /// Recurring interval, e.g. `rate(5 minutes)`. Runs are aligned to the Unix epoch
+    /// so every replica and every restart computes the same fire times. Elapsed-time
+    /// based, so unaffected by the schedule's timezone and DST.
+    Rate(TimeDelta),
+    /// One-time run at a wall-clock time in the schedule's timezone, e.g. `at(2026-11-01T09:00:00)`
+    At(NaiveDateTime),
+}
+
+impl FromStr for ScheduleExpression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(inner) = s.strip_prefix("rate(").and_then(|r| r.strip_suffix(')')) {
+            return parse_rate(inner).map(ScheduleExpression::Rate);
+        }
+
+        if let Some(inner) = s.strip_prefix("at(").and_then(|r| r.strip_suffix(')')) {
+            return NaiveDateTime::parse_from_str(inner.trim(), AT_FORMAT)
+                .map(ScheduleExpression::At)
+                .map_err(|_| format!("Invalid at() expression '{}', expected at(yyyy-mm-ddThh:mm:ss)", s));
+        }
+
+        Cron::from_str(s)
+            .map(|cron| ScheduleExpression::Cron(Box::new(cron)))
+            .map_err(|e| format!("Invalid cron expression '{}': {}", s, e))
+    }
+}
+
+fn parse_rate(inner: &str) -> Result<TimeDelta, String> {
+    let invalid = || format!("Invalid rate() expression 'rate({})', expected e.g. rate(5 minutes)", inner);
+
+    let mut parts = inner.split_whitespace();
+    let (Some(value), Some(unit), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let value: i64 = value.parse().map_err(|_| invalid())?;
+    if value <= 0 {
+        return Err(invalid());
+    }
+
+    match unit {
+        "minute" | "minutes" => Ok(TimeDelta::minutes(value)),
+        "hour" | "hours" => Ok(TimeDelta::hours(value)),
+        "day" | "days" => Ok(TimeDelta::days(value)),
+        _ => Err(invalid()),
+    }
+}
+
+impl ScheduleExpression {
+    /// One-time schedules disable themselves after firing.
+    pub fn is_one_time(&self) -> bool {
+        matches!(self, ScheduleExpression::At(_))
+    }
+
+    /// Next fire time strictly after `after`, or `None` if the schedule will not fire again.
+    pub fn next_after(
+        &self,
+        after: DateTime<Utc>,
+        tz: &Tz,
+        gap_policy: DstGapPolicy,
+    ) -> Result<Option<DateTime<Utc>>, String> {
+        match self {
+            ScheduleExpression::Cron(cron) => next_cron_after(cron, after, tz, gap_policy),
+            ScheduleExpression::Rate(interval) => {
+                let interval_secs = interval.num_seconds();
+                let elapsed = after.timestamp().div_euclid(interval_secs);
+                Ok(DateTime::from_timestamp((elapsed + 1) * interval_secs, 0))
+            }
+            ScheduleExpression::At(local) => {
+                Ok(resolve_local(local, tz, gap_policy).filter(|at| *at > after))
+            }
+        }
+    }
+
+    /// Latest fire time at or before `at`, or `None` if the schedule never fired by then.
+    pub fn last_at_or_before(
+        &self,
+        at: DateTime<Utc>,
+        tz: &Tz,
+        gap_policy: DstGapPolicy,
+    ) -> Result<Option<DateTime<Utc>>, String> {
+        match self {
+            ScheduleExpression::Cron(cron) => last_cron_at_or_before(cron, at, tz, gap_policy),
+            ScheduleExpression::Rate(interval) => {
+                let interval_secs = interval.num_seconds();
+                let elapsed = at.timestamp().div_euclid(interval_secs);
+                Ok(DateTime::from_timestamp(elapsed * interval_secs, 0))
+            }
+            ScheduleExpression::At(local) => {
+                Ok(resolve_local(local, tz, gap_policy).filter(|run| *run <= at))
+            }
+        }
+    }
+}
+
+/// Walks the cron matches in wall-clock time and maps each one to an instant in `tz`.
+/// Matching on wall-clock time rather than letting croner resolve the timezone keeps
+/// the DST rules in one place: gap runs follow `gap_policy`, and a wall-clock time
+/// that occurs twice on fall-back only fires on its first occurrence.
+fn next_cron_after(
+    cron: &Cron,
+    after: DateTime<Utc>,
+    tz: &Tz,
+    gap_policy: DstGapPolicy,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let mut wall = after.with_timezone(tz).naive_local();
+
+    for _ in 0..MAX_SEARCH_STEPS {
+        wall = cron
+            .find_next_occurrence(&Utc.from_utc_datetime(&wall), false)
+            .map_err(|e| format!("No next occurrence for '{}': {}", cron, e))?
+            .naive_utc();
+
+        if let Some(instant) = resolve_local(&wall, tz, gap_policy) {
+            if instant > after {
+                return Ok(Some(instant));
+            }
+        }
+    }
+
+    Err(format!("No next occurrence for '{}' within {} runs", cron, MAX_SEARCH_STEPS))
+}
+
+/// Walks the cron matches backwards in wall-clock time, with the same DST rules as
+/// `next_cron_after`.
+fn last_cron_at_or_before(
+    cron: &Cron,
+    at: DateTime<Utc>,
+    tz: &Tz,
+    gap_policy: DstGapPolicy,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let mut wall = at.with_timezone(tz).naive_local();
+
+    for step in 0..MAX_SEARCH_STEPS {
+        wall = match cron.find_previous_occurrence(&Utc.from_utc_datetime(&wall), step == 0) {
+            Ok(previous) => previous.naive_utc(),
+            Err(_) => return Ok(None),
+        };
+
+        if let Some(instant) = resolve_local(&wall, tz, gap_policy) {
+            if instant <= at {
+                return Ok(Some(instant));
+            }
+        }
+    }
+
+    Err(format!("No previous occurrence for '{}' within {} runs", cron, MAX_SEARCH_STEPS))
+}
+
+/// Maps a wall-clock time in `tz` to an instant, applying the DST rules.
+fn resolve_local(local: &NaiveDateTime, tz: &Tz, gap_policy: DstGapPolicy) -> Option<DateTime<Utc>> {
+    match tz.from_local_datetime(local) {
+        LocalResult::Single(dt) => Some(dt.with_timezone(&Utc)),
+        // Fall-back: fire once, on the first occurrence
+        LocalResult::Ambiguous(earliest, _) => Some(earliest.with_timezone(&Utc)),
+        // Spring-forward: the wall-clock time does not exist
+        LocalResult::None => match gap_policy {
+            DstGapPolicy::Skip => None,
+            DstGapPolicy::Shift => {
+                // Interpret the time with the offset in force before the gap,
+                // which lands it the gap length past the transition
+                let before_gap = (1..=24 * 60)
+                    .map(|minutes| *local - TimeDelta::minutes(minutes))
+                    .find_map(|earlier| tz.from_local_datetime(&earlier).earliest())?;
+                let offset = before_gap.offset().fix();
+                Some(Utc.from_utc_datetime(&(*local - offset)))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Europe/Berlin in 2026: spring-forward on March 29 (02:00 CET -> 03:00 CEST),
+    // fall-back on October 25 (03:00 CEST -> 02:00 CET).
+    const BERLIN: Tz = chrono_tz::Europe::Berlin;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(expr: &str, after: &str, policy: DstGapPolicy) -> Option<DateTime<Utc>> {
+        ScheduleExpression::from_str(expr)
+            .unwrap()
+            .next_after(utc(after), &BERLIN, policy)
+            .unwrap()
+    }
+
+    #[test]
+    fn cron_is_evaluated_in_schedule_timezone() {
+        // 09:00 CEST in summer, 09:00 CET in winter
+        assert_eq!(next("0 9 * * *", "2026-07-01T00:00:00Z", DstGapPolicy::Shift), Some(utc("2026-07-01T07:00:00Z")));
+        assert_eq!(next("0 9 * * *", "2026-12-01T00:00:00Z", DstGapPolicy::Shift), Some(utc("2026-12-01T08:00:00Z")));
+    }
+
+    #[test]
+    fn spring_forward_shift_moves_run_past_gap() {
+        // 02:30 does not exist on March 29; it runs at 03:30 CEST instead
+        assert_eq!(next("30 2 * * *", "2026-03-28T12:00:00Z", DstGapPolicy::Shift), Some(utc("2026-03-29T01:30:00Z")));
+    }
+
+    #[test]
+    fn spring_forward_skip_drops_run() {
+        // The March 29 run is dropped, the next one is 02:30 CEST on March 30
+        assert_eq!(next("30 2 * * *", "2026-03-28T12:00:00Z", DstGapPolicy::Skip), Some(utc("2026-03-30T00:30:00Z")));
+    }
+
+    #[test]
+    fn spring_forward_interval_keeps_order() {
+        // */30 from 01:30 CET: 02:00 is shifted to 03:00 CEST, then 03:30 CEST
+        let first = next("*/30 * * * *", "2026-03-29T00:30:00Z", DstGapPolicy::Shift).unwrap();
+        assert_eq!(first, utc("2026-03-29T01:00:00Z"));
+        let second = next("*/30 * * * *", &first.to_rfc3339(), DstGapPolicy::Shift).unwrap();
+        assert_eq!(second, utc("2026-03-29T01:30:00Z"));
+
+        // With skip, 02:00 and 02:30 are dropped and the next run is 03:00 CEST
+        assert_eq!(next("*/30 * * * *", "2026-03-29T00:30:00Z", DstGapPolicy::Skip), Some(utc("2026-03-29T01:00:00Z")));
+    }
+
+    #[test]
+    fn fall_back_fixed_time_fires_once() {
+        // 02:30 happens twice on October 25; only the first (CEST) occurrence fires
+        let first = next("30 2 * * *", "2026-10-24T12:00:00Z", DstGapPolicy::Shift).unwrap();
+        assert_eq!(first, utc("2026-10-25T00:30:00Z"));
+        let second = next("30 2 * * *", &first.to_rfc3339(), DstGapPolicy::Shift).unwrap();
+        assert_eq!(second, utc("2026-10-26T01:30:00Z"));
+    }
+
+    #[test]
+    fn fall_back_interval_does_not_repeat_hour() {
+        // */30 through the repeated hour: 02:00 CEST, 02:30 CEST, then 03:00 CET
+        let mut after = utc("2026-10-24T23:45:00Z");
+        let mut runs = Vec::new();
+        for _ in 0..3 {
+            after = next("*/30 * * * *", &after.to_rfc3339(), DstGapPolicy::Shift).unwrap();
+            runs.push(after);
+        }
+        assert_eq!(
+            runs,
+            vec![utc("2026-10-25T00:00:00Z"), utc("2026-10-25T00:30:00Z"), utc("2026-10-25T02:00:00Z")]
+        );
+    }
+
+    #[test]
+    fn fall_back_never_returns_time_in_the_past() {
+        // 02:10 CET, during the second pass of the repeated hour: 02:30 already ran at 00:30 UTC
+        assert_eq!(next("30 2 * * *", "2026-10-25T01:10:00Z", DstGapPolicy::Shift), Some(utc("2026-10-26T01:30:00Z")));
+    }
+
+    #[test]
+    fn at_in_gap_follows_policy() {
+        assert_eq!(next("at(2026-03-29T02:30:00)", "2026-03-01T00:00:00Z", DstGapPolicy::Shift), Some(utc("2026-03-29T01:30:00Z")));
+        assert_eq!(next("at(2026-03-29T02:30:00)", "2026-03-01T00:00:00Z", DstGapPolicy::Skip), None);
+    }
+
+    #[test]
+    fn at_in_overlap_fires_on_first_occurrence() {
+        assert_eq!(next("at(2026-10-25T02:30:00)", "2026-10-01T00:00:00Z", DstGapPolicy::Shift), Some(utc("2026-10-25T00:30:00Z")));
+        assert_eq!(next("at(2026-10-25T02:30:00)", "2026-10-25T00:30:00Z", DstGapPolicy::Shift), None);
+    }
+
+    fn last(expr: &str, at: &str, policy: DstGapPolicy) -> Option<DateTime<Utc>> {
+        ScheduleExpression::from_str(expr)
+            .unwrap()
+            .last_at_or_before(utc(at), &BERLIN, policy)
+            .unwrap()
+    }
+
+    #[test]
+    fn last_run_is_found_without_walking_forward() {
+        assert_eq!(last("0 9 * * *", "2026-03-10T08:00:00Z", DstGapPolicy::Shift), Some(utc("2026-03-10T08:00:00Z")));
+        assert_eq!(last("0 9 * * *", "2026-03-10T07:59:59Z", DstGapPolicy::Shift), Some(utc("2026-03-09T08:00:00Z")));
+        assert_eq!(last("rate(5 minutes)", "2026-03-10T08:07:00Z", DstGapPolicy::Shift), Some(utc("2026-03-10T08:05:00Z")));
+        assert_eq!(last("at(2026-03-10T09:00:00)", "2026-03-10T07:00:00Z", DstGapPolicy::Shift), None);
+        // The 02:30 run on spring-forward day is skipped, so the day before's run is the last
+        assert_eq!(last("30 2 * * *", "2026-03-29T06:00:00Z", DstGapPolicy::Skip), Some(utc("2026-03-28T01:30:00Z")));
+    }
+
+    #[test]
+    fn rate_is_unaffected_by_dst() {
+        assert_eq!(next("rate(1 hour)", "2026-03-29T00:30:00Z", DstGapPolicy::Skip), Some(utc("2026-03-29T01:00:00Z")));
+        assert_eq!(next("rate(1 hour)", "2026-10-25T00:30:00Z", DstGapPolicy::Skip), Some(utc("2026-10-25T01:00:00Z")));
+    }
+}
@@ -0,0 +1,14 @@
+// lib.rs
+// Storage models shared by nats_consumer and nats_web through the sled DB.
+
+pub mod circuit;
+pub mod cloud_event;
+pub mod dead_letter;
+pub mod envelope;
+pub mod expression;
+pub mod pattern;
+pub mod retry_policy;
+pub mod rule;
+pub mod schedule;
+pub mod topology;
+pub mod transform;
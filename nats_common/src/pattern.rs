@@ -0,0 +1,262 @@
+// pattern.rs
+// EventBridge-compatible event patterns, see
+// https://docs.aws.amazon.com/eventbridge/latest/userguide/eb-event-patterns.html
+use serde_json::{Map, Value};
+
+/// Checks that `pattern` is a well-formed event pattern, so a broken rule is
+/// rejected when it is saved rather than silently never matching.
+pub fn validate_pattern(pattern: &Value) -> Result<(), String> {
+    let Value::Object(fields) = pattern else {
+        return Err("Event pattern must be a JSON object".to_string());
+    };
+    for (key, value) in fields {
+        match value {
+            Value::Object(_) => validate_pattern(value).map_err(|e| format!("{}: {}", key, e))?,
+            Value::Array(matchers) => {
+                if matchers.is_empty() {
+                    return Err(format!("{}: list of values must not be empty", key));
+                }
+                for matcher in matchers {
+                    validate_matcher(matcher).map_err(|e| format!("{}: {}", key, e))?;
+                }
+            }
+            _ => return Err(format!("{}: values must be listed in an array, e.g. [\"value\"]", key)),
+        }
+    }
+    Ok(())
+}
+
+fn validate_matcher(matcher: &Value) -> Result<(), String> {
+    let Value::Object(operator) = matcher else {
+        return Ok(());
+    };
+    let (Some((name, operand)), 1) = (operator.iter().next(), operator.len()) else {
+        return Err("a content filter must have exactly one operator".to_string());
+    };
+    match (name.as_str(), operand) {
+        ("prefix" | "suffix", Value::String(_)) => Ok(()),
+        ("prefix" | "suffix", Value::Object(inner)) if is_ignore_case(inner) => Ok(()),
+        ("equals-ignore-case", Value::String(_)) => Ok(()),
+        ("exists", Value::Bool(_)) => Ok(()),
+        ("numeric", Value::Array(conditions)) => parse_numeric(conditions).map(|_| ()),
+        ("anything-but", Value::Object(inner))
+            if inner.keys().all(|k| matches!(k.as_str(), "prefix" | "suffix" | "equals-ignore-case")) =>
+        {
+            validate_matcher(operand)
+        }
+        ("anything-but", Value::Array(values)) if values.iter().all(|v| !v.is_object()) => Ok(()),
+        ("anything-but", value) if !value.is_object() && !value.is_array() => Ok(()),
+        (name, _) => Err(format!("unsupported content filter '{}'", name)),
+    }
+}
+
+fn is_ignore_case(inner: &Map<String, Value>) -> bool {
+    inner.len() == 1 && inner.get("equals-ignore-case").is_some_and(Value::is_string)
+}
+
+/// Whether `event` matches `pattern`. Every field in the pattern must match; a field
+/// matches if any of its listed values or content filters matches.
+pub fn matches(pattern: &Value, event: &Value) -> bool {
+    matches_fields(pattern, Some(event))
+}
+
+// A missing parent, or one that is not an object, has none of the nested fields, so
+// only `exists: false` can match under it
+fn matches_fields(pattern: &Value, event: Option<&Value>) -> bool {
+    let Value::Object(fields) = pattern else {
+        return false;
+    };
+    fields.iter().all(|(key, expected)| {
+        let actual = event.and_then(|event| event.get(key));
+        match expected {
+            Value::Object(_) => matches_fields(expected, actual.filter(|nested| nested.is_object())),
+            Value::Array(matchers) => matchers.iter().any(|m| matches_field(m, actual)),
+            _ => false,
+        }
+    })
+}
+
+fn matches_field(matcher: &Value, actual: Option<&Value>) -> bool {
+    if let Some(exists) = matcher.get("exists").and_then(Value::as_bool) {
+        return exists == actual.is_some();
+    }
+    match actual {
+        None => false,
+        // An array in the event matches if any of its elements matches
+        Some(Value::Array(values)) => values.iter().any(|value| matches_value(matcher, value)),
+        Some(value) => matches_value(matcher, value),
+    }
+}
+
+fn matches_value(matcher: &Value, value: &Value) -> bool {
+    let Value::Object(operator) = matcher else {
+        return equals(matcher, value);
+    };
+    let Some((name, operand)) = operator.iter().next() else {
+        return false;
+    };
+    match name.as_str() {
+        "prefix" => string_filter(operand, value, |v, p| v.starts_with(p)),
+        "suffix" => string_filter(operand, value, |v, s| v.ends_with(s)),
+        "equals-ignore-case" => match (operand.as_str(), value.as_str()) {
+            (Some(expected), Some(value)) => expected.to_lowercase() == value.to_lowercase(),
+            _ => false,
+        },
+        "numeric" => match (operand.as_array().and_then(|c| parse_numeric(c).ok()), value.as_f64()) {
+            (Some(conditions), Some(number)) => conditions.iter().all(|(op, bound)| compare(op, number, *bound)),
+            _ => false,
+        },
+        "anything-but" => match operand {
+            Value::Array(excluded) => !excluded.iter().any(|e| equals(e, value)),
+            Value::Object(_) => value.is_string() && !matches_value(operand, value),
+            excluded => !equals(excluded, value),
+        },
+        _ => false,
+    }
+}
+
+/// `prefix`/`suffix` take a string, or `{"equals-ignore-case": string}` to compare case-insensitively.
+fn string_filter(operand: &Value, value: &Value, test: fn(&str, &str) -> bool) -> bool {
+    let Some(value) = value.as_str() else {
+        return false;
+    };
+    match operand {
+        Value::String(expected) => test(value, expected),
+        Value::Object(inner) => inner
+            .get("equals-ignore-case")
+            .and_then(Value::as_str)
+            .is_some_and(|expected| test(&value.to_lowercase(), &expected.to_lowercase())),
+        _ => false,
+    }
+}
+
+// Numbers compare by value, so 5 matches 5.0
+fn equals(expected: &Value, actual: &Value) -> bool {
+    match (expected.as_f64(), actual.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => expected == actual,
+    }
+}
+
+/// Parses `[">", 0, "<=", 5]` into operator/bound pairs.
+fn parse_numeric(conditions: &[Value]) -> Result<Vec<(String, f64)>, String> {
+    if conditions.is_empty() || !conditions.len().is_multiple_of(2) {
+        return Err("numeric expects operator/value pairs, e.g. [\">\", 0, \"<=\", 5]".to_string());
+    }
+    conditions
+        .chunks(2)
+        .map(|pair| match (pair[0].as_str(), pair[1].as_f64()) {
+            (Some(op @ ("<" | "<=" | "=" | ">" | ">=")), Some(bound)) => Ok((op.to_string(), bound)),
+            _ => Err(format!("invalid numeric condition {} {}", pair[0], pair[1])),
+        })
+        .collect()
+}
+
+fn compare(op: &str, number: f64, bound: f64) -> bool {
+    match op {
+        "<" => number < bound,
+        "<=" => number <= bound,
+        "=" => number == bound,
+        ">" => number > bound,
+        ">=" => number >= bound,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn order(status: &str, amount: f64) -> Value {
+        json!({"source": "orders", "detail": {"status": status, "amount": amount, "tags": ["new", "priority"]}})
+    }
+
+    #[test]
+    fn literal_values_and_nested_fields() {
+        let pattern = json!({"source": ["orders", "billing"], "detail": {"status": ["paid"]}});
+        assert!(matches(&pattern, &order("paid", 5.0)));
+        assert!(!matches(&pattern, &order("open", 5.0)));
+        assert!(!matches(&json!({"source": ["billing"]}), &order("paid", 5.0)));
+        // Numbers compare by value
+        assert!(matches(&json!({"detail": {"amount": [5]}}), &order("paid", 5.0)));
+    }
+
+    #[test]
+    fn array_fields_match_if_any_element_matches() {
+        assert!(matches(&json!({"detail": {"tags": ["priority"]}}), &order("paid", 5.0)));
+        assert!(!matches(&json!({"detail": {"tags": ["archived"]}}), &order("paid", 5.0)));
+    }
+
+    #[test]
+    fn prefix_suffix_and_equals_ignore_case() {
+        let event = json!({"file": "Reports/2025.CSV"});
+        assert!(matches(&json!({"file": [{"prefix": "Reports/"}]}), &event));
+        assert!(!matches(&json!({"file": [{"prefix": "reports/"}]}), &event));
+        assert!(matches(&json!({"file": [{"prefix": {"equals-ignore-case": "reports/"}}]}), &event));
+        assert!(matches(&json!({"file": [{"suffix": ".CSV"}]}), &event));
+        assert!(matches(&json!({"file": [{"suffix": {"equals-ignore-case": ".csv"}}]}), &event));
+        assert!(matches(&json!({"file": [{"equals-ignore-case": "reports/2025.csv"}]}), &event));
+        assert!(!matches(&json!({"file": [{"equals-ignore-case": "reports"}]}), &event));
+        // String filters never match other types
+        assert!(!matches(&json!({"n": [{"prefix": "1"}]}), &json!({"n": 12})));
+    }
+
+    #[test]
+    fn anything_but_lists_values_or_nests_an_operator() {
+        let pattern = json!({"detail": {"status": [{"anything-but": ["cancelled", "refunded"]}]}});
+        assert!(matches(&pattern, &order("paid", 5.0)));
+        assert!(!matches(&pattern, &order("refunded", 5.0)));
+        assert!(matches(&json!({"detail": {"status": [{"anything-but": "open"}]}}), &order("paid", 5.0)));
+
+        let nested = json!({"detail": {"status": [{"anything-but": {"prefix": "test-"}}]}});
+        assert!(matches(&nested, &order("paid", 5.0)));
+        assert!(!matches(&nested, &order("test-paid", 5.0)));
+        // A missing field matches no value, not even anything-but
+        assert!(!matches(&json!({"missing": [{"anything-but": "x"}]}), &order("paid", 5.0)));
+    }
+
+    #[test]
+    fn numeric_ranges() {
+        let pattern = json!({"detail": {"amount": [{"numeric": [">", 0, "<=", 100]}]}});
+        assert!(matches(&pattern, &order("paid", 100.0)));
+        assert!(!matches(&pattern, &order("paid", 0.0)));
+        assert!(!matches(&pattern, &order("paid", 100.5)));
+        assert!(matches(&json!({"detail": {"amount": [{"numeric": ["=", 5]}]}}), &order("paid", 5.0)));
+        assert!(!matches(&json!({"detail": {"status": [{"numeric": [">", 0]}]}}), &order("paid", 5.0)));
+    }
+
+    #[test]
+    fn exists_checks_presence_even_under_a_missing_parent() {
+        let event = order("paid", 5.0);
+        assert!(matches(&json!({"detail": {"status": [{"exists": true}]}}), &event));
+        assert!(!matches(&json!({"detail": {"refund": [{"exists": true}]}}), &event));
+        assert!(matches(&json!({"detail": {"refund": [{"exists": false}]}}), &event));
+
+        let without_detail = json!({"source": "orders"});
+        assert!(matches(&json!({"detail": {"x": [{"exists": false}]}}), &without_detail));
+        assert!(matches(&json!({"detail": {"x": {"y": [{"exists": false}]}}}), &without_detail));
+        assert!(!matches(&json!({"detail": {"x": [{"exists": true}]}}), &without_detail));
+        assert!(!matches(&json!({"detail": {"x": ["value"]}}), &without_detail));
+        // A parent that is not an object has no fields either
+        assert!(matches(&json!({"source": {"x": [{"exists": false}]}}), &without_detail));
+    }
+
+    #[test]
+    fn validation_rejects_malformed_patterns() {
+        assert!(validate_pattern(&json!({"detail": {"status": ["paid", {"prefix": "p"}]}})).is_ok());
+        assert!(validate_pattern(&json!({"detail": {"amount": [{"numeric": [">=", 1, "<", 5]}]}})).is_ok());
+        assert!(validate_pattern(&json!({"status": [{"anything-but": {"suffix": ".tmp"}}]})).is_ok());
+
+        let error = |pattern: Value| validate_pattern(&pattern).unwrap_err();
+        assert!(error(json!(["paid"])).contains("must be a JSON object"));
+        assert!(error(json!({"status": "paid"})).contains("listed in an array"));
+        assert!(error(json!({"status": []})).contains("must not be empty"));
+        assert!(error(json!({"detail": {"status": [{"regex": "p.*"}]}})).starts_with("detail: status: unsupported content filter"));
+        assert!(error(json!({"status": [{"prefix": "a", "suffix": "b"}]})).contains("exactly one operator"));
+        assert!(error(json!({"amount": [{"numeric": [">", 0, "<"]}]})).contains("operator/value pairs"));
+        assert!(error(json!({"amount": [{"numeric": ["!=", 0]}]})).contains("invalid numeric condition"));
+        assert!(error(json!({"status": [{"anything-but": [{"prefix": "a"}]}]})).contains("unsupported"));
+        assert!(error(json!({"status": [{"exists": "yes"}]})).contains("unsupported"));
+    }
+}
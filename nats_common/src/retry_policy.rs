@@ -0,0 +1,152 @@
+// retry_policy.rs
+// How a rule's targets are retried after a failed invocation, modelled on the
+// EventBridge target retry policy plus an exponential backoff.
+use serde::{Deserialize, Serialize};
+
+// Limits EventBridge applies to its retry policies
+const MAX_RETRY_ATTEMPTS: u32 = 185;
+const MIN_EVENT_AGE_SECS: u64 = 60;
+const MAX_EVENT_AGE_SECS: u64 = 86400;
+// The fixed retry delays rules used before they had a retry policy
+const DEFAULT_DELAYS_SECS: [u64; 6] = [600, 1800, 3600, 14400, 28800, 86400];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,               // Retries after the first invocation; 0 disables retries
+    pub max_event_age_secs: Option<u64>, // Dead-letter instead of retrying once the event would be older
+    pub base_delay_secs: u64,            // Delay before the first retry
+    pub multiplier: f64,                 // Each retry waits this many times longer than the one before
+    pub max_delay_secs: u64,             // Cap on a single delay
+    pub jitter: f64,                     // Fraction of each delay that is random, from 0 to 1
+    #[serde(default)]
+    pub delays_secs: Vec<u64>,           // Fixed delay of each retry, the last one repeating; replaces the backoff when set
+}
+
+impl Default for RetryPolicy {
+    /// 6 retries after 10 minutes, 30 minutes, 1, 4, 8 and 24 hours. Without fixed delays the
+    /// backoff waits 1 minute, 4 minutes, 16 minutes, ... up to a day.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 6,
+            max_event_age_secs: None,
+            base_delay_secs: 60,
+            multiplier: 4.0,
+            max_delay_secs: 86400,
+            jitter: 0.0,
+            delays_secs: DEFAULT_DELAYS_SECS.to_vec(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts > MAX_RETRY_ATTEMPTS {
+            return Err(format!("Max retry attempts must be at most {}", MAX_RETRY_ATTEMPTS));
+        }
+        if let Some(age) = self.max_event_age_secs {
+            if !(MIN_EVENT_AGE_SECS..=MAX_EVENT_AGE_SECS).contains(&age) {
+                return Err(format!(
+                    "Max event age must be between {} and {} seconds",
+                    MIN_EVENT_AGE_SECS, MAX_EVENT_AGE_SECS
+                ));
+            }
+        }
+        if self.base_delay_secs == 0 {
+            return Err("Base delay must be at least 1 second".to_string());
+        }
+        if !self.multiplier.is_finite() || self.multiplier < 1.0 {
+            return Err("Multiplier must be at least 1".to_string());
+        }
+        if self.max_delay_secs < self.base_delay_secs {
+            return Err("Max delay must not be shorter than the base delay".to_string());
+        }
+        if self.delays_secs.contains(&0) {
+            return Err("Fixed delays must be at least 1 second".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err("Jitter must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn retries_enabled(&self) -> bool {
+        self.max_attempts > 0
+    }
+
+    /// Delay before retry number `retry` (1 for the first retry). `random` is a sample
+    /// in `[0, 1)`; the jittered part of the delay is scaled by it.
+    pub fn delay_secs(&self, retry: u64, random: f64) -> u64 {
+        let index = retry.saturating_sub(1);
+        let backoff = match self.delays_secs.last() {
+            Some(last) => *self.delays_secs.get(index as usize).unwrap_or(last) as f64,
+            None => {
+                let exponent = index.min(i32::MAX as u64) as i32;
+                (self.base_delay_secs as f64 * self.multiplier.powi(exponent)).min(self.max_delay_secs as f64)
+            }
+        };
+        let jittered = backoff * (1.0 - self.jitter * random.clamp(0.0, 1.0));
+        (jittered.round() as u64).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(base_delay_secs: u64, multiplier: f64, max_delay_secs: u64) -> RetryPolicy {
+        RetryPolicy {
+            base_delay_secs,
+            multiplier,
+            max_delay_secs,
+            delays_secs: Vec::new(),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn default_keeps_the_fixed_delays() {
+        let policy = RetryPolicy::default();
+        let delays: Vec<u64> = (1..=7).map(|retry| policy.delay_secs(retry, 0.5)).collect();
+        assert_eq!(delays, [600, 1800, 3600, 14400, 28800, 86400, 86400]);
+        assert!(policy.validate().is_ok());
+
+        // Policies stored before fixed delays existed keep their backoff
+        let stored: RetryPolicy = serde_json::from_str(r#"{"max_attempts": 3, "base_delay_secs": 30}"#).unwrap();
+        assert!(stored.delays_secs.is_empty());
+        assert_eq!(stored.delay_secs(2, 0.0), 120);
+    }
+
+    #[test]
+    fn backoff_grows_by_the_multiplier_up_to_the_max_delay() {
+        let policy = backoff(60, 4.0, 3600);
+        let delays: Vec<u64> = (1..=5).map(|retry| policy.delay_secs(retry, 0.0)).collect();
+        assert_eq!(delays, [60, 240, 960, 3600, 3600]);
+        assert_eq!(policy.delay_secs(u64::MAX, 0.0), 3600);
+    }
+
+    #[test]
+    fn jitter_shortens_delays_by_up_to_its_fraction() {
+        let policy = RetryPolicy { jitter: 0.5, ..backoff(100, 1.0, 100) };
+        assert_eq!(policy.delay_secs(1, 0.0), 100);
+        assert_eq!(policy.delay_secs(1, 0.5), 75);
+        assert_eq!(policy.delay_secs(1, 1.0), 50);
+        // Never shorter than a second
+        assert_eq!(RetryPolicy { jitter: 1.0, ..backoff(1, 1.0, 1) }.delay_secs(1, 1.0), 1);
+    }
+
+    #[test]
+    fn validate_applies_the_eventbridge_limits() {
+        assert!(backoff(60, 2.0, 600).validate().is_ok());
+        assert!(RetryPolicy { max_attempts: 186, ..RetryPolicy::default() }.validate().is_err());
+        assert!(RetryPolicy { max_event_age_secs: Some(59), ..RetryPolicy::default() }.validate().is_err());
+        assert!(RetryPolicy { max_event_age_secs: Some(86401), ..RetryPolicy::default() }.validate().is_err());
+        assert!(RetryPolicy { max_event_age_secs: Some(3600), ..RetryPolicy::default() }.validate().is_ok());
+        assert!(backoff(0, 2.0, 600).validate().is_err());
+        assert!(backoff(60, 0.5, 600).validate().is_err());
+        assert!(backoff(60, f64::NAN, 600).validate().is_err());
+        assert!(backoff(600, 2.0, 60).validate().is_err());
+        assert!(RetryPolicy { jitter: 1.5, ..RetryPolicy::default() }.validate().is_err());
+        assert!(RetryPolicy { delays_secs: vec![60, 0], ..RetryPolicy::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,412 @@
+// rule.rs
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::Db;
+use std::sync::{Arc, Mutex};
+
+use crate::cloud_event::{self, to_cloud_event};
+use crate::dead_letter::original_payload;
+use crate::pattern;
+use crate::retry_policy::RetryPolicy;
+use crate::transform::TargetInput;
+
+pub const RULE_PREFIX: &str = "rule:";
+
+/// A Lambda function invoked for every event a rule matches. Each target is
+/// invoked and retried on its own, so one failing target does not re-trigger the others.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    pub id: String,
+    pub lambda_arn: String,
+    #[serde(default)]
+    pub input: Option<TargetInput>,  // Reshapes the event for this target; None sends the whole event
+}
+
+impl Target {
+    /// Payload the target's Lambda is invoked with.
+    pub fn payload(&self, event: &Value) -> Value {
+        match &self.input {
+            Some(input) => input.apply(event),
+            None => event.clone(),
+        }
+    }
+}
+
+/// How targets are invoked, named after the Lambda `InvocationType`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InvocationMode {
+    /// Asynchronous; failures are found later in CloudWatch Logs by the status checker
+    #[default]
+    Event,
+    /// Synchronous; the result decides success or retry right away
+    RequestResponse,
+}
+
+/// What the targets of a rule are invoked with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryFormat {
+    /// The event, or the target's input, as it is
+    #[default]
+    Plain,
+    /// A structured CloudEvent whose data is the event, or the target's input
+    CloudEvents,
+}
+
+/// Routing rule stored as JSON under `rule:<event_type>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    pub event_type: String,          // Matched against the event's `event_type`, or a CloudEvent's `type`; names the rule when it has a pattern
+    pub targets: Vec<Target>,
+    #[serde(default)]
+    pub pattern: Option<Value>,      // EventBridge event pattern; replaces the event_type match when set
+    #[serde(default)]
+    pub invocation_mode: InvocationMode,
+    #[serde(default)]
+    pub reply_subject: Option<String>, // RequestResponse only: subject the function's response is published to
+    #[serde(default)]
+    pub result_subject: Option<String>, // Subject completion events are published to; `{event_type}` is replaced
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    #[serde(default)]
+    pub rate_limit: Option<f64>,     // Invocations per second of each target's function; None for no limit
+    #[serde(default)]
+    pub delivery_format: DeliveryFormat,
+}
+
+impl Rule {
+    /// Whether the rule routes `event` to its targets.
+    pub fn matches(&self, event: &Value) -> bool {
+        match &self.pattern {
+            Some(pattern) => pattern::matches(pattern, event),
+            None => cloud_event::event_type(event) == Some(self.event_type.as_str()),
+        }
+    }
+
+    /// Payload `target` is invoked with for `event`, whose id is `event_id`. A retry carries
+    /// the fields added while tracking it; they are dropped, so every attempt gets the same input.
+    pub fn target_payload(&self, target: &Target, event: &Value, event_id: &str) -> Value {
+        let event = original_payload(event);
+        match self.delivery_format {
+            DeliveryFormat::Plain => target.payload(&event),
+            DeliveryFormat::CloudEvents => {
+                let data = target.input.as_ref().map(|input| input.apply(&event));
+                to_cloud_event(&event, data, event_id, &self.event_type)
+            }
+        }
+    }
+
+    /// Subject completion events of this rule's targets are published to, e.g.
+    /// `lambda.result.{event_type}` becomes `lambda.result.order.created`.
+    pub fn resolved_result_subject(&self) -> Option<String> {
+        self.result_subject
+            .as_ref()
+            .map(|subject| subject.replace("{event_type}", &self.event_type))
+    }
+
+    pub fn target(&self, id: &str) -> Option<&Target> {
+        self.targets.iter().find(|t| t.id == id)
+    }
+}
+
+/// Checks that `event_type` can be used in a NATS subject: dot-separated tokens that are
+/// not empty and have no whitespace, control characters or `*`/`>` wildcards.
+pub fn validate_event_type(event_type: &str) -> Result<(), String> {
+    if event_type.is_empty() {
+        return Err("Event type is required".to_string());
+    }
+    if event_type.split('.').any(str::is_empty) {
+        return Err(format!("Event type '{}' must not start or end with '.' or contain '..'", event_type));
+    }
+    if event_type.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '*' | '>')) {
+        return Err(format!("Event type '{}' must not contain whitespace or the wildcards '*' and '>'", event_type));
+    }
+    Ok(())
+}
+
+fn rule_key(event_type: &str) -> String {
+    format!("{}{}", RULE_PREFIX, event_type)
+}
+
+// Keys that are neither rules nor legacy rules
+fn is_reserved_key(key: &str) -> bool {
+    key.starts_with("aws_") || key.contains(':')
+}
+
+/// Rules from before fan-out were stored as a bare `event_type` -> `lambda_arn` key.
+fn legacy_rule(event_type: &str, value: &[u8]) -> Option<Rule> {
+    let lambda_arn = String::from_utf8(value.to_vec()).ok()?;
+    Some(Rule {
+        event_type: event_type.to_string(),
+        targets: vec![Target {
+            id: "0".to_string(),
+            lambda_arn,
+            input: None,
+        }],
+        pattern: None,
+        invocation_mode: InvocationMode::default(),
+        reply_subject: None,
+        result_subject: None,
+        retry_policy: RetryPolicy::default(),
+        rate_limit: None,
+        delivery_format: DeliveryFormat::Plain,
+    })
+}
+
+/// Moves legacy rules under `RULE_PREFIX`, so loading rules only has to scan that prefix.
+/// A legacy key shadowed by a rule for the same event type is dropped. Run at startup.
+pub fn migrate_legacy_rules(db: &Db) -> sled::Result<usize> {
+    let legacy_keys: Vec<String> = db
+        .iter()
+        .keys()
+        .flatten()
+        .filter_map(|key| String::from_utf8(key.to_vec()).ok())
+        .filter(|key| !is_reserved_key(key))
+        .collect();
+
+    let mut migrated = 0;
+    for event_type in legacy_keys {
+        if db.contains_key(rule_key(&event_type).as_bytes())? {
+            db.remove(event_type.as_bytes())?;
+            continue;
+        }
+        if let Some(rule) = db.get(event_type.as_bytes())?.and_then(|value| legacy_rule(&event_type, &value)) {
+            save_rule(db, &rule)?;
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
+
+/// Every stored rule. A rule that cannot be parsed is logged and left out.
+pub fn load_rules(db: &Db) -> Vec<Rule> {
+    db.scan_prefix(RULE_PREFIX)
+        .flatten()
+        .filter_map(|(key, value)| parse_rule(&key, &value))
+        .collect()
+}
+
+fn parse_rule(key: &[u8], value: &[u8]) -> Option<Rule> {
+    serde_json::from_slice(value)
+        .inspect_err(|e| eprintln!("❌ Rule {} cannot be parsed, its events are not routed: {}", String::from_utf8_lossy(key), e))
+        .ok()
+}
+
+/// Parsed rules, loaded on first use and again after any rule changed, so routing an
+/// event does not read and parse every rule.
+pub struct RuleCache {
+    db: Db,
+    rules: Arc<Mutex<Option<Arc<Vec<Rule>>>>>, // None until loaded, and again once a rule changed
+}
+
+impl RuleCache {
+    pub fn new(db: Db) -> Self {
+        let rules = Arc::new(Mutex::new(None));
+        // Subscribed before the first load, so no change is missed
+        let changes = db.watch_prefix(RULE_PREFIX);
+        let cached = Arc::downgrade(&rules);
+        std::thread::spawn(move || {
+            for _ in changes {
+                let Some(rules) = cached.upgrade() else {
+                    break;
+                };
+                *rules.lock().unwrap() = None;
+            }
+        });
+        RuleCache { db, rules }
+    }
+
+    pub fn rules(&self) -> Arc<Vec<Rule>> {
+        self.rules
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| Arc::new(load_rules(&self.db)))
+            .clone()
+    }
+
+    /// Rules whose event type or pattern matches `event`.
+    pub fn matching(&self, event: &Value) -> Vec<Rule> {
+        self.rules().iter().filter(|rule| rule.matches(event)).cloned().collect()
+    }
+}
+
+pub fn get_rule(db: &Db, event_type: &str) -> sled::Result<Option<Rule>> {
+    let key = rule_key(event_type);
+    if let Some(value) = db.get(key.as_bytes())? {
+        return Ok(parse_rule(key.as_bytes(), &value));
+    }
+    if is_reserved_key(event_type) {
+        return Ok(None);
+    }
+    Ok(db
+        .get(event_type.as_bytes())?
+        .and_then(|value| legacy_rule(event_type, &value)))
+}
+
+/// Stores the rule, replacing a legacy key for the same event type. A rule
+/// without targets is removed.
+pub fn save_rule(db: &Db, rule: &Rule) -> sled::Result<()> {
+    if !is_reserved_key(&rule.event_type) {
+        db.remove(rule.event_type.as_bytes())?;
+    }
+    if rule.targets.is_empty() {
+        db.remove(rule_key(&rule.event_type).as_bytes())?;
+        return Ok(());
+    }
+    let value = serde_json::to_vec(rule).expect("Rule is always serializable");
+    db.insert(rule_key(&rule.event_type).as_bytes(), value)?;
+    Ok(())
+}
+
+/// Adds a target to the rule for `event_type`, creating the rule if needed, and
+/// sets the rule's pattern when one is given. Adding a Lambda that is already a
+/// target replaces that target's input.
+pub fn add_target(
+    db: &Db,
+    event_type: &str,
+    lambda_arn: &str,
+    pattern: Option<Value>,
+    input: Option<TargetInput>,
+) -> sled::Result<Rule> {
+    let mut rule = get_rule(db, event_type)?.unwrap_or_else(|| Rule {
+        event_type: event_type.to_string(),
+        targets: Vec::new(),
+        pattern: None,
+        invocation_mode: InvocationMode::default(),
+        reply_subject: None,
+        result_subject: None,
+        retry_policy: RetryPolicy::default(),
+        rate_limit: None,
+        delivery_format: DeliveryFormat::Plain,
+    });
+    if pattern.is_some() {
+        rule.pattern = pattern;
+    }
+    match rule.targets.iter_mut().find(|t| t.lambda_arn == lambda_arn) {
+        Some(existing) => existing.input = input,
+        None => rule.targets.push(Target {
+            id: db.generate_id()?.to_string(),
+            lambda_arn: lambda_arn.to_string(),
+            input,
+        }),
+    }
+    save_rule(db, &rule)?;
+    Ok(rule)
+}
+
+/// Removes one target, deleting the rule once its last target is gone.
+pub fn remove_target(db: &Db, event_type: &str, target_id: &str) -> sled::Result<bool> {
+    let Some(mut rule) = get_rule(db, event_type)? else {
+        return Ok(false);
+    };
+    let before = rule.targets.len();
+    rule.targets.retain(|t| t.id != target_id);
+    if rule.targets.len() == before {
+        return Ok(false);
+    }
+    save_rule(db, &rule)?;
+    Ok(true)
+}
+
+pub fn delete_rule(db: &Db, event_type: &str) -> sled::Result<bool> {
+    let removed = db.remove(rule_key(event_type).as_bytes())?.is_some();
+    let removed_legacy = !is_reserved_key(event_type) && db.remove(event_type.as_bytes())?.is_some();
+    Ok(removed || removed_legacy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn a_retry_is_invoked_with_the_same_input_as_the_first_attempt() {
+        let event = json!({"event_type": "order.created", "id": "e1", "detail": {"order_id": 7}});
+        // What the status checker republishes after a failed attempt
+        let mut retry = event.clone();
+        retry["retry_index"] = 1.into();
+        retry["event_id"] = "e1".into();
+        retry["rule"] = "order.created".into();
+        retry["target_id"] = "0".into();
+        retry["lambda_arn"] = "arn:aws:lambda:us-east-1:123456789012:function:orders".into();
+        retry["lambda_request_id"] = "request-1".into();
+        retry["attempts"] = json!([{"retry_index": 0, "request_id": "request-1", "invoked_at": "2025-01-01T00:00:00Z"}]);
+        retry["retry_policy"] = serde_json::to_value(RetryPolicy::default()).unwrap();
+        retry["result_subject"] = "lambda.result.order.created".into();
+
+        let mut rule = legacy_rule("order.created", b"arn:aws:lambda:us-east-1:123456789012:function:orders").unwrap();
+        let whole_event = rule.targets[0].clone();
+        let with_path = Target {
+            input: Some(TargetInput::Path { path: "$".to_string() }),
+            ..whole_event.clone()
+        };
+        for format in [DeliveryFormat::Plain, DeliveryFormat::CloudEvents] {
+            rule.delivery_format = format;
+            for target in [&whole_event, &with_path] {
+                let first = rule.target_payload(target, &event, "e1");
+                assert_eq!(rule.target_payload(target, &retry, "e1"), first);
+                if format == DeliveryFormat::Plain {
+                    assert_eq!(first, event);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn event_types_must_be_valid_subject_tokens() {
+        assert!(validate_event_type("order.created").is_ok());
+        assert!(validate_event_type("order-created_v2").is_ok());
+        for invalid in ["", "order created", "order.*", "order.>", "order..created", ".order", "order.", "order\tcreated"] {
+            assert!(validate_event_type(invalid).is_err(), "{:?} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn rule_cache_reloads_after_a_rule_changed() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cache = RuleCache::new(db.clone());
+        let event = json!({"event_type": "order.created"});
+        assert!(cache.matching(&event).is_empty());
+
+        add_target(&db, "order.created", "arn:aws:lambda:us-east-1:123456789012:function:orders", None, None).unwrap();
+        // The cache is cleared by a watcher thread
+        let mut matching = Vec::new();
+        for _ in 0..100 {
+            matching = cache.matching(&event);
+            if !matching.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(matching.len(), 1);
+
+        // A rule that cannot be parsed is left out, not fatal
+        db.insert(rule_key("broken"), "{").unwrap();
+        assert!(get_rule(&db, "broken").unwrap().is_none());
+        assert_eq!(load_rules(&db).len(), 1);
+    }
+
+    #[test]
+    fn legacy_rules_are_migrated_under_the_rule_prefix() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert("order.created", "arn:aws:lambda:us-east-1:123456789012:function:orders").unwrap();
+        db.insert("aws_region", "us-east-1").unwrap();
+        add_target(&db, "order.paid", "arn:aws:lambda:us-east-1:123456789012:function:billing", None, None).unwrap();
+        db.insert("order.paid", "arn:aws:lambda:us-east-1:123456789012:function:stale").unwrap();
+
+        // Only prefixed keys are loaded
+        assert_eq!(load_rules(&db).len(), 1);
+
+        assert_eq!(migrate_legacy_rules(&db).unwrap(), 1);
+        let mut rules = load_rules(&db);
+        rules.sort_by(|a, b| a.event_type.cmp(&b.event_type));
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].event_type, "order.created");
+        assert_eq!(rules[0].targets[0].lambda_arn, "arn:aws:lambda:us-east-1:123456789012:function:orders");
+        assert_eq!(rules[1].targets[0].lambda_arn, "arn:aws:lambda:us-east-1:123456789012:function:billing");
+
+        assert!(db.get("order.created").unwrap().is_none());
+        assert!(db.get("order.paid").unwrap().is_none());
+        assert!(db.get("aws_region").unwrap().is_some());
+        assert_eq!(migrate_legacy_rules(&db).unwrap(), 0);
+    }
+}
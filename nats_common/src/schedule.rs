@@ -0,0 +1,287 @@
+// schedule.rs
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::Db;
+use std::str::FromStr;
+
+use crate::expression::{DstGapPolicy, ScheduleExpression};
+
+pub const SCHEDULE_PREFIX: &str = "schedule:";
+const MAX_CATCH_UP_RUNS: usize = 100_000;
+// Same upper bound as EventBridge Scheduler's flexible time window
+const MAX_FLEXIBLE_WINDOW_MINUTES: u32 = 1440;
+
+/// What the scheduler does on startup with runs that were due while it was down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum CatchUpPolicy {
+    /// Drop missed runs and wait for the next regular one
+    #[default]
+    Skip,
+    /// Fire a single event for the most recent missed run
+    FireOnce,
+    /// Fire one event per missed run, oldest first. Only the newest `max_runs` are fired;
+    /// older missed runs are dropped
+    FireAll { max_runs: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: String,
+    pub event_type: String,          // Event type published when the schedule fires (e.g., "daily.backup")
+    pub lambda_arn: String,          // ARN of the Lambda function the schedule is meant for
+    pub expression: String,          // Cron, rate() or at() expression (e.g., "0 9 * * *", "rate(5 minutes)")
+    #[serde(default)]
+    pub payload: Option<Value>,      // Extra fields merged into the published event
+    #[serde(default = "default_timezone")]
+    pub timezone: String,            // IANA timezone the expression is evaluated in
+    #[serde(default)]
+    pub dst_gap: DstGapPolicy,       // Whether runs in a spring-forward gap are shifted or skipped
+    #[serde(default)]
+    pub catch_up: CatchUpPolicy,     // What to do with runs missed while nats_consumer was down
+    #[serde(default)]
+    pub flexible_window_minutes: Option<u32>, // Publish at a random time up to this long after each run is due
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub updated_at: Option<String>, // RFC 3339 time the schedule was created, edited or resumed; runs before it are not caught up
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Schedule {
+    /// Checks everything the scheduler needs before the record is stored.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.event_type.trim().is_empty() {
+            return Err("Event type is required".to_string());
+        }
+        self.parsed_expression()?;
+        self.tz()?;
+        if let Some(window) = self.flexible_window_minutes {
+            if window == 0 || window > MAX_FLEXIBLE_WINDOW_MINUTES {
+                return Err(format!(
+                    "Flexible time window must be between 1 and {} minutes",
+                    MAX_FLEXIBLE_WINDOW_MINUTES
+                ));
+            }
+        }
+        if let Some(payload) = &self.payload {
+            if !payload.is_object() {
+                return Err("Payload must be a JSON object".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parsed_expression(&self) -> Result<ScheduleExpression, String> {
+        ScheduleExpression::from_str(&self.expression)
+    }
+
+    pub fn tz(&self) -> Result<Tz, String> {
+        Tz::from_str(self.timezone.trim())
+            .map_err(|_| format!("Unknown timezone '{}'", self.timezone))
+    }
+
+    /// Next fire time strictly after `after`, evaluated in the schedule's timezone.
+    /// Returns `None` once a one-time schedule has passed.
+    pub fn next_fire(&self, after: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+        self.parsed_expression()?
+            .next_after(after, &self.tz()?, self.dst_gap)
+    }
+
+    /// Where catch-up starts: the last run that was published, or when the schedule was
+    /// created or last changed if that is later. `None` if neither is known.
+    pub fn catch_up_baseline(&self, last_fire: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+        let updated_at = self
+            .updated_at
+            .as_deref()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        last_fire.max(updated_at)
+    }
+
+    /// Runs that came due after `last_fire` and up to `now`, oldest first, filtered by the
+    /// catch-up policy. They are found walking back from `now`, so when there are more than
+    /// the policy fires, the oldest are dropped, however long the downtime was.
+    pub fn missed_runs(&self, last_fire: DateTime<Utc>, now: DateTime<Utc>) -> Result<Vec<DateTime<Utc>>, String> {
+        let limit = match self.catch_up {
+            CatchUpPolicy::Skip => return Ok(Vec::new()),
+            CatchUpPolicy::FireOnce => 1,
+            CatchUpPolicy::FireAll { max_runs } => (max_runs as usize).min(MAX_CATCH_UP_RUNS),
+        };
+        let expression = self.parsed_expression()?;
+        let tz = self.tz()?;
+
+        let mut missed = Vec::new();
+        let mut cursor = now;
+        while missed.len() < limit {
+            match expression.last_at_or_before(cursor, &tz, self.dst_gap)? {
+                Some(run) if run > last_fire => {
+                    missed.push(run);
+                    // Fire times are whole seconds
+                    cursor = run - TimeDelta::seconds(1);
+                }
+                _ => break,
+            }
+        }
+        missed.reverse();
+        Ok(missed)
+    }
+
+    /// Event published to `my.event` when the schedule fires. `scheduled_time` is when the
+    /// run was due and `actual_time` when it was published; they differ for catch-up runs.
+    pub fn event_payload(&self, scheduled_time: DateTime<Utc>, actual_time: DateTime<Utc>) -> Value {
+        let mut event = match &self.payload {
+            Some(Value::Object(fields)) => Value::Object(fields.clone()),
+            _ => Value::Object(Default::default()),
+        };
+        event["event_type"] = self.event_type.clone().into();
+        event["schedule_id"] = self.id.clone().into();
+        event["scheduled_time"] = scheduled_time.to_rfc3339().into();
+        event["actual_time"] = actual_time.to_rfc3339().into();
+        event
+    }
+
+    /// Parses a sled entry, accepting the legacy `schedule:<event_type>` -> `lambda_arn:cron` format.
+    fn from_entry(key: &[u8], value: &[u8]) -> Option<Schedule> {
+        if let Ok(schedule) = serde_json::from_slice::<Schedule>(value) {
+            return Some(schedule);
+        }
+
+        let key_str = String::from_utf8(key.to_vec()).ok()?;
+        let event_type = key_str.strip_prefix(SCHEDULE_PREFIX)?.to_string();
+        let value_str = String::from_utf8(value.to_vec()).ok()?;
+        // ARNs contain colons but cron expressions do not
+        let (lambda_arn, expression) = value_str.rsplit_once(':')?;
+
+        Some(Schedule {
+            id: event_type.clone(),
+            event_type,
+            lambda_arn: lambda_arn.to_string(),
+            expression: expression.to_string(),
+            payload: None,
+            timezone: default_timezone(),
+            dst_gap: DstGapPolicy::default(),
+            catch_up: CatchUpPolicy::default(),
+            flexible_window_minutes: None,
+            enabled: true,
+            updated_at: None,
+        })
+    }
+}
+
+fn schedule_key(id: &str) -> String {
+    format!("{}{}", SCHEDULE_PREFIX, id)
+}
+
+pub fn load_schedules(db: &Db) -> Vec<Schedule> {
+    db.scan_prefix(SCHEDULE_PREFIX)
+        .flatten()
+        .filter_map(|(key, value)| Schedule::from_entry(&key, &value))
+        .collect()
+}
+
+pub fn get_schedule(db: &Db, id: &str) -> sled::Result<Option<Schedule>> {
+    let key = schedule_key(id);
+    Ok(db
+        .get(key.as_bytes())?
+        .and_then(|value| Schedule::from_entry(key.as_bytes(), &value)))
+}
+
+pub fn save_schedule(db: &Db, schedule: &Schedule) -> sled::Result<()> {
+    let value = serde_json::to_vec(schedule).expect("Schedule is always serializable");
+    db.insert(schedule_key(&schedule.id).as_bytes(), value)?;
+    Ok(())
+}
+
+pub fn delete_schedule(db: &Db, id: &str) -> sled::Result<bool> {
+    Ok(db.remove(schedule_key(id).as_bytes())?.is_some())
+}
+
+/// Generates a new unique schedule id.
+pub fn new_schedule_id(db: &Db) -> sled::Result<String> {
+    Ok(db.generate_id()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hourly(catch_up: CatchUpPolicy) -> Schedule {
+        Schedule {
+            id: "1".to_string(),
+            event_type: "hourly.report".to_string(),
+            lambda_arn: "arn:aws:lambda:us-east-1:123456789012:function:report".to_string(),
+            expression: "0 * * * *".to_string(),
+            payload: None,
+            timezone: default_timezone(),
+            dst_gap: DstGapPolicy::default(),
+            catch_up,
+            flexible_window_minutes: None,
+            enabled: true,
+            updated_at: None,
+        }
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn skip_fires_no_missed_runs() {
+        let missed = hourly(CatchUpPolicy::Skip).missed_runs(at("2025-01-01T08:00:00Z"), at("2025-01-01T12:30:00Z"));
+        assert!(missed.unwrap().is_empty());
+    }
+
+    #[test]
+    fn fire_once_fires_the_most_recent_missed_run() {
+        let schedule = hourly(CatchUpPolicy::FireOnce);
+        let missed = schedule.missed_runs(at("2025-01-01T08:00:00Z"), at("2025-01-01T12:30:00Z"));
+        assert_eq!(missed.unwrap(), vec![at("2025-01-01T12:00:00Z")]);
+        // Nothing was missed if the next run is not due yet
+        let missed = schedule.missed_runs(at("2025-01-01T12:00:00Z"), at("2025-01-01T12:30:00Z"));
+        assert!(missed.unwrap().is_empty());
+        // Not a stale run after a downtime of more than 100,000 runs
+        let missed = schedule.missed_runs(at("2000-01-01T00:00:00Z"), at("2025-01-01T12:30:00Z"));
+        assert_eq!(missed.unwrap(), vec![at("2025-01-01T12:00:00Z")]);
+    }
+
+    #[test]
+    fn fire_all_fires_the_newest_missed_runs_oldest_first() {
+        let missed = hourly(CatchUpPolicy::FireAll { max_runs: 10 })
+            .missed_runs(at("2025-01-01T08:00:00Z"), at("2025-01-01T11:00:00Z"));
+        assert_eq!(
+            missed.unwrap(),
+            vec![at("2025-01-01T09:00:00Z"), at("2025-01-01T10:00:00Z"), at("2025-01-01T11:00:00Z")]
+        );
+
+        let missed = hourly(CatchUpPolicy::FireAll { max_runs: 2 })
+            .missed_runs(at("2025-01-01T08:00:00Z"), at("2025-01-01T12:30:00Z"));
+        assert_eq!(missed.unwrap(), vec![at("2025-01-01T11:00:00Z"), at("2025-01-01T12:00:00Z")]);
+
+        // A downtime with more runs than could be scanned forward still ends at the newest
+        let missed = hourly(CatchUpPolicy::FireAll { max_runs: 2 })
+            .missed_runs(at("2000-01-01T00:00:00Z"), at("2025-01-01T12:30:00Z"));
+        assert_eq!(missed.unwrap(), vec![at("2025-01-01T11:00:00Z"), at("2025-01-01T12:00:00Z")]);
+    }
+
+    #[test]
+    fn catch_up_starts_at_the_later_of_last_fire_and_last_change() {
+        let mut schedule = hourly(CatchUpPolicy::FireOnce);
+        assert_eq!(schedule.catch_up_baseline(None), None);
+        assert_eq!(schedule.catch_up_baseline(Some(at("2025-01-01T08:00:00Z"))), Some(at("2025-01-01T08:00:00Z")));
+
+        schedule.updated_at = Some("2025-01-01T09:30:00Z".to_string());
+        assert_eq!(schedule.catch_up_baseline(None), Some(at("2025-01-01T09:30:00Z")));
+        assert_eq!(schedule.catch_up_baseline(Some(at("2025-01-01T08:00:00Z"))), Some(at("2025-01-01T09:30:00Z")));
+        assert_eq!(schedule.catch_up_baseline(Some(at("2025-01-01T10:00:00Z"))), Some(at("2025-01-01T10:00:00Z")));
+    }
+}
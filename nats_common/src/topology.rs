@@ -0,0 +1,262 @@
+// topology.rs
+// Streams, subjects and durable consumers events flow through, shared by nats_consumer
+// and nats_web so both sides publish and consume the same subjects:
+//
+//   event_subject ──▶ event_stream ──(trigger_durable)──▶ trigger ──▶ status_subject
+//   status_subject ──▶ status_stream ──(status_durable)──▶ status checker ──▶ event_subject (retry)
+//                                                                        └──▶ dead_letter_subject
+//   trigger ──(circuit open)──▶ holding_subject_prefix ──▶ holding_stream ──(holding_durable)──▶ releaser ──▶ event_subject
+use serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::circuit::circuit_key;
+use std::fs;
+
+/// Path of an optional JSON file with topology fields; environment variables override it.
+pub const TOPOLOGY_FILE_ENV: &str = "NATS_TOPOLOGY_FILE";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Topology {
+    pub event_stream: String,          // Captures events and completion events
+    pub event_subject: String,         // Events, scheduled events, retries and redrives
+    pub result_subject_prefix: String, // Completion events; the event stream captures `<prefix>>`
+    pub trigger_durable: String,
+    pub status_stream: String,
+    pub status_subject: String,        // Delayed status checks of Event mode invocations
+    pub status_durable: String,
+    pub dead_letter_stream: String,
+    pub dead_letter_subject: String,
+    pub holding_stream: String,        // Events parked while their target's circuit is open
+    pub holding_subject_prefix: String, // One subject per function, `<prefix><circuit key>`
+    pub holding_durable: String,
+    pub circuit_bucket: String,        // KV bucket with the circuit of each function
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Topology {
+            event_stream: "my_bridge".to_string(),
+            event_subject: "my.event".to_string(),
+            result_subject_prefix: "lambda.result.".to_string(),
+            trigger_durable: "lambda_trigger".to_string(),
+            status_stream: "status_bridge".to_string(),
+            status_subject: "check.lambda.status".to_string(),
+            status_durable: "status_checker".to_string(),
+            dead_letter_stream: "dead_letter".to_string(),
+            dead_letter_subject: "dead.letter".to_string(),
+            holding_stream: "held_events".to_string(),
+            holding_subject_prefix: "held.event.".to_string(),
+            holding_durable: "held_event_releaser".to_string(),
+            circuit_bucket: "lambda_circuits".to_string(),
+        }
+    }
+}
+
+impl Topology {
+    /// Defaults, then the file named by `NATS_TOPOLOGY_FILE`, then one `NATS_<FIELD>`
+    /// variable per field (e.g. `NATS_STATUS_SUBJECT`). The result is validated.
+    pub fn load() -> Result<Self, String> {
+        let mut topology = match env::var(TOPOLOGY_FILE_ENV) {
+            Ok(path) => {
+                let contents = fs::read_to_string(&path).map_err(|e| format!("Cannot read topology file {}: {}", path, e))?;
+                serde_json::from_str(&contents).map_err(|e| format!("Invalid topology file {}: {}", path, e))?
+            }
+            Err(_) => Topology::default(),
+        };
+
+        for (name, field) in topology.fields_mut() {
+            if let Ok(value) = env::var(format!("NATS_{}", name.to_uppercase())) {
+                *field = value;
+            }
+        }
+
+        topology.validate()?;
+        Ok(topology)
+    }
+
+    fn fields_mut(&mut self) -> [(&'static str, &mut String); 13] {
+        [
+            ("event_stream", &mut self.event_stream),
+            ("event_subject", &mut self.event_subject),
+            ("result_subject_prefix", &mut self.result_subject_prefix),
+            ("trigger_durable", &mut self.trigger_durable),
+            ("status_stream", &mut self.status_stream),
+            ("status_subject", &mut self.status_subject),
+            ("status_durable", &mut self.status_durable),
+            ("dead_letter_stream", &mut self.dead_letter_stream),
+            ("dead_letter_subject", &mut self.dead_letter_subject),
+            ("holding_stream", &mut self.holding_stream),
+            ("holding_subject_prefix", &mut self.holding_subject_prefix),
+            ("holding_durable", &mut self.holding_durable),
+            ("circuit_bucket", &mut self.circuit_bucket),
+        ]
+    }
+
+    /// Subjects captured by the event stream.
+    pub fn event_stream_subjects(&self) -> Vec<String> {
+        vec![self.event_subject.clone(), format!("{}>", self.result_subject_prefix)]
+    }
+
+    pub fn is_result_subject(&self, subject: &str) -> bool {
+        subject.starts_with(&self.result_subject_prefix)
+    }
+
+    /// Subject events for the function are parked on while its circuit is open.
+    pub fn holding_subject(&self, lambda_arn: &str) -> String {
+        format!("{}{}", self.holding_subject_prefix, circuit_key(lambda_arn))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("event_stream", &self.event_stream),
+            ("status_stream", &self.status_stream),
+            ("dead_letter_stream", &self.dead_letter_stream),
+            ("holding_stream", &self.holding_stream),
+            ("circuit_bucket", &self.circuit_bucket),
+            ("trigger_durable", &self.trigger_durable),
+            ("status_durable", &self.status_durable),
+            ("holding_durable", &self.holding_durable),
+        ] {
+            validate_name(value).map_err(|e| format!("{}: {}", name, e))?;
+        }
+        for (name, value) in [
+            ("event_subject", &self.event_subject),
+            ("status_subject", &self.status_subject),
+            ("dead_letter_subject", &self.dead_letter_subject),
+        ] {
+            validate_subject(value).map_err(|e| format!("{}: {}", name, e))?;
+        }
+        for (name, value) in [
+            ("result_subject_prefix", &self.result_subject_prefix),
+            ("holding_subject_prefix", &self.holding_subject_prefix),
+        ] {
+            let prefix = value.strip_suffix('.').ok_or(format!("{}: must end with '.'", name))?;
+            validate_subject(prefix).map_err(|e| format!("{}: {}", name, e))?;
+        }
+
+        let streams = [&self.event_stream, &self.status_stream, &self.dead_letter_stream, &self.holding_stream];
+        if streams.iter().enumerate().any(|(i, a)| streams[i + 1..].contains(a)) {
+            return Err("Stream names must be different".to_string());
+        }
+        if self.trigger_durable == self.status_durable {
+            return Err("trigger_durable and status_durable must be different".to_string());
+        }
+        // A subject can only belong to one stream
+        if self.event_subject == self.status_subject
+            || self.event_subject == self.dead_letter_subject
+            || self.status_subject == self.dead_letter_subject
+        {
+            return Err("event_subject, status_subject and dead_letter_subject must be different".to_string());
+        }
+        for (name, subject) in [
+            ("event_subject", &self.event_subject),
+            ("status_subject", &self.status_subject),
+            ("dead_letter_subject", &self.dead_letter_subject),
+        ] {
+            if self.is_result_subject(subject) {
+                return Err(format!("{} must not start with result_subject_prefix", name));
+            }
+            if subject.starts_with(&self.holding_subject_prefix) {
+                return Err(format!("{} must not start with holding_subject_prefix", name));
+            }
+        }
+        if self.result_subject_prefix.starts_with(&self.holding_subject_prefix)
+            || self.holding_subject_prefix.starts_with(&self.result_subject_prefix)
+        {
+            return Err("result_subject_prefix and holding_subject_prefix must not overlap".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Stream and consumer names end up in subjects and file names
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if name.chars().any(|c| c.is_whitespace() || matches!(c, '.' | '*' | '>' | '/' | '\\')) {
+        return Err(format!("'{}' must not contain whitespace, '.', '*', '>', '/' or '\\'", name));
+    }
+    Ok(())
+}
+
+// A subject messages are published to, so without wildcards
+fn validate_subject(subject: &str) -> Result<(), String> {
+    if subject.split('.').any(|token| token.is_empty()) {
+        return Err(format!("'{}' has an empty token", subject));
+    }
+    if subject.chars().any(|c| c.is_whitespace() || matches!(c, '*' | '>')) {
+        return Err(format!("'{}' must not contain whitespace or wildcards", subject));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_topology_is_valid() {
+        let topology = Topology::default();
+        assert_eq!(topology.validate(), Ok(()));
+        assert_eq!(topology.event_stream_subjects(), vec!["my.event", "lambda.result.>"]);
+    }
+
+    #[test]
+    fn status_subject_must_not_be_captured_by_the_event_stream() {
+        let topology = Topology {
+            status_subject: "lambda.result.status".to_string(),
+            ..Topology::default()
+        };
+        assert!(topology.validate().is_err());
+    }
+
+    #[test]
+    fn holding_stream_needs_its_own_name_and_subjects() {
+        for topology in [
+            Topology {
+                holding_stream: "dead_letter".to_string(),
+                ..Topology::default()
+            },
+            Topology {
+                holding_subject_prefix: "lambda.result.held.".to_string(),
+                ..Topology::default()
+            },
+            Topology {
+                dead_letter_subject: "held.event.dead".to_string(),
+                ..Topology::default()
+            },
+        ] {
+            assert!(topology.validate().is_err(), "{:?} should be rejected", topology);
+        }
+    }
+
+    #[test]
+    fn consumers_need_their_own_durable_names() {
+        let topology = Topology {
+            status_durable: "lambda_trigger".to_string(),
+            ..Topology::default()
+        };
+        assert!(topology.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_wildcards_and_empty_tokens() {
+        for subject in ["my.*", "my.>", "my..event", "", "my event"] {
+            let topology = Topology {
+                event_subject: subject.to_string(),
+                ..Topology::default()
+            };
+            assert!(topology.validate().is_err(), "{:?} should be rejected", subject);
+        }
+    }
+
+    #[test]
+    fn file_fields_default_and_unknown_fields_are_rejected() {
+        let topology: Topology = serde_json::from_str(r#"{"status_subject": "my.status"}"#).unwrap();
+        assert_eq!(topology.status_subject, "my.status");
+        assert_eq!(topology.event_subject, "my.event");
+        assert!(serde_json::from_str::<Topology>(r#"{"status_subjct": "my.status"}"#).is_err());
+    }
+}
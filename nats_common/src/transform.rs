@@ -0,0 +1,228 @@
+// transform.rs
+// Per-target input transformation, modelled on EventBridge target input:
+// https://docs.aws.amazon.com/eventbridge/latest/userguide/eb-transform-target-input.html
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// What a target receives instead of the whole event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum TargetInput {
+    /// The same JSON document on every invocation, whatever the event
+    Constant { json: Value },
+    /// The part of the event selected by a JSONPath such as `$.detail`
+    Path { path: String },
+    /// EventBridge `InputPathsMap` plus `InputTemplate`: each `<name>` in the
+    /// template is replaced with the value at the mapped path
+    Transformer {
+        paths_map: BTreeMap<String, String>,
+        template: String,
+    },
+}
+
+impl TargetInput {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            TargetInput::Constant { .. } => Ok(()),
+            TargetInput::Path { path } => parse_path(path).map(|_| ()),
+            TargetInput::Transformer { paths_map, template } => {
+                for (name, path) in paths_map {
+                    parse_path(path).map_err(|e| format!("{}: {}", name, e))?;
+                }
+                if template.trim().is_empty() {
+                    return Err("Input template is required".to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Builds the Lambda payload for `event`. Paths that select nothing yield `null`.
+    pub fn apply(&self, event: &Value) -> Value {
+        match self {
+            TargetInput::Constant { json } => json.clone(),
+            TargetInput::Path { path } => select(event, path).cloned().unwrap_or(Value::Null),
+            TargetInput::Transformer { paths_map, template } => {
+                let rendered = render_template(template, paths_map, event);
+                // A template that is not JSON, e.g. a plain message, is sent as a JSON string
+                serde_json::from_str(&rendered).unwrap_or(Value::String(rendered))
+            }
+        }
+    }
+}
+
+/// Replaces each `<name>` placeholder. As in EventBridge, strings are inserted without
+/// quotes, so the template decides whether they end up as JSON strings (`"<name>"`),
+/// while numbers, booleans, objects and arrays are inserted as JSON. The template is
+/// scanned once, so a `<name>` inside an inserted value is never substituted again.
+fn render_template(template: &str, paths_map: &BTreeMap<String, String>, event: &Value) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('<') {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let path = after
+            .find('>')
+            .and_then(|end| paths_map.get(&after[..end]).map(|path| (end, path)));
+        match path {
+            Some((end, path)) => {
+                rendered.push_str(&render_value(select(event, path)));
+                rest = &after[end + 1..];
+            }
+            None => {
+                // Not a mapped placeholder, e.g. a literal `<` in the template
+                rendered.push('<');
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn render_value(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(s)) => {
+            // Escape quotes and backslashes so the value cannot break out of its JSON string
+            let quoted = serde_json::to_string(s).unwrap_or_default();
+            quoted[1..quoted.len() - 1].to_string()
+        }
+        Some(value) => value.to_string(),
+        None => "null".to_string(),
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+/// Parses the JSONPath subset EventBridge accepts in input paths: `$`, `.field`,
+/// `['field']` and `[index]`.
+fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let invalid = |reason: &str| format!("Invalid JSONPath '{}': {}", path, reason);
+    let mut rest = path.trim().strip_prefix('$').ok_or_else(|| invalid("must start with $"))?;
+    let mut segments = Vec::new();
+
+    while !rest.is_empty() {
+        if let Some(after_dot) = rest.strip_prefix('.') {
+            let end = after_dot.find(['.', '[']).unwrap_or(after_dot.len());
+            if end == 0 {
+                return Err(invalid("empty field name"));
+            }
+            segments.push(Segment::Field(after_dot[..end].to_string()));
+            rest = &after_dot[end..];
+        } else if let Some(after_bracket) = rest.strip_prefix('[') {
+            let end = after_bracket.find(']').ok_or_else(|| invalid("unclosed ["))?;
+            let inner = &after_bracket[..end];
+            let quoted = inner
+                .strip_prefix('\'')
+                .and_then(|i| i.strip_suffix('\''))
+                .or_else(|| inner.strip_prefix('"').and_then(|i| i.strip_suffix('"')));
+            match quoted {
+                Some(field) => segments.push(Segment::Field(field.to_string())),
+                None => segments.push(Segment::Index(
+                    inner.parse().map_err(|_| invalid("index must be a number"))?,
+                )),
+            }
+            rest = &after_bracket[end + 1..];
+        } else {
+            return Err(invalid("expected . or ["));
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Value at `path` in `event`, or `None` if the path is invalid or selects nothing.
+pub fn select<'a>(event: &'a Value, path: &str) -> Option<&'a Value> {
+    parse_path(path)
+        .ok()?
+        .iter()
+        .try_fold(event, |value, segment| match segment {
+            Segment::Field(name) => value.get(name),
+            Segment::Index(index) => value.get(index),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event() -> Value {
+        json!({
+            "id": "abc",
+            "detail": {"order-id": 42, "items": [{"sku": "A1"}, {"sku": "B2"}], "note": "say \"hi\"\\"}
+        })
+    }
+
+    fn transformer(paths: &[(&str, &str)], template: &str) -> TargetInput {
+        TargetInput::Transformer {
+            paths_map: paths.iter().map(|(n, p)| (n.to_string(), p.to_string())).collect(),
+            template: template.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_path_accepts_the_eventbridge_subset() {
+        assert!(parse_path("$").unwrap().is_empty());
+        let segments = parse_path("$.detail['order-id']").unwrap();
+        assert!(matches!(&segments[..], [Segment::Field(a), Segment::Field(b)] if a == "detail" && b == "order-id"));
+        assert!(matches!(&parse_path("$.items[1].sku").unwrap()[..], [_, Segment::Index(1), _]));
+        assert!(matches!(&parse_path("$[\"id\"]").unwrap()[..], [Segment::Field(f)] if f == "id"));
+
+        assert!(parse_path("detail").unwrap_err().contains("must start with $"));
+        assert!(parse_path("$..detail").unwrap_err().contains("empty field name"));
+        assert!(parse_path("$.items[0").unwrap_err().contains("unclosed ["));
+        assert!(parse_path("$.items[first]").unwrap_err().contains("index must be a number"));
+        assert!(parse_path("$detail").unwrap_err().contains("expected . or ["));
+    }
+
+    #[test]
+    fn path_selects_part_of_the_event() {
+        let input = TargetInput::Path { path: "$.detail.items[1]".to_string() };
+        assert_eq!(input.apply(&event()), json!({"sku": "B2"}));
+        assert_eq!(TargetInput::Path { path: "$".to_string() }.apply(&event()), event());
+        // Missing paths select null
+        let missing = TargetInput::Path { path: "$.detail.items[5]".to_string() };
+        assert_eq!(missing.apply(&event()), Value::Null);
+    }
+
+    #[test]
+    fn transformer_renders_json_and_plain_text() {
+        let input = transformer(
+            &[("id", "$.id"), ("order", "$.detail.order-id"), ("items", "$.detail.items"), ("gone", "$.nope")],
+            r#"{"event": "<id>", "order": <order>, "items": <items>, "gone": <gone>}"#,
+        );
+        assert_eq!(
+            input.apply(&event()),
+            json!({"event": "abc", "order": 42, "items": [{"sku": "A1"}, {"sku": "B2"}], "gone": null})
+        );
+
+        let text = transformer(&[("id", "$.id")], "Event <id> for <unmapped> is 1 < 2");
+        assert_eq!(text.apply(&event()), json!("Event abc for <unmapped> is 1 < 2"));
+    }
+
+    #[test]
+    fn transformer_escapes_strings_inside_quotes() {
+        let input = transformer(&[("note", "$.detail.note")], r#"{"note": "<note>"}"#);
+        assert_eq!(input.apply(&event()), json!({"note": "say \"hi\"\\"}));
+    }
+
+    #[test]
+    fn transformer_does_not_substitute_placeholders_in_event_values() {
+        let event = json!({"a": "<b>", "b": "secret"});
+        let input = transformer(&[("a", "$.a"), ("b", "$.b")], r#"{"a": "<a>", "b": "<b>"}"#);
+        assert_eq!(input.apply(&event), json!({"a": "<b>", "b": "secret"}));
+    }
+
+    #[test]
+    fn validate_reports_the_invalid_path() {
+        assert!(transformer(&[("id", "$.id")], "<id>").validate().is_ok());
+        assert!(transformer(&[("id", "id")], "<id>").validate().unwrap_err().starts_with("id: Invalid JSONPath"));
+        assert_eq!(transformer(&[], " ").validate().unwrap_err(), "Input template is required");
+    }
+}
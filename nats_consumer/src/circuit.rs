@@ -0,0 +1,262 @@
+// circuit.rs
+// Circuit breaker per Lambda function. Failures are counted in the topology's circuit KV
+// bucket, so every replica sees the same circuits and the web UI can show and reset them.
+use async_nats::jetstream::{self, kv};
+use async_nats::{Client, HeaderMap};
+use anyhow::Result;
+use chrono::{TimeDelta, Utc};
+use futures::StreamExt;
+use nats_common::circuit::{circuit_key, Admission, Circuit, CircuitState};
+use nats_common::envelope::{republish_msg_id, MSG_ID_HEADER};
+use nats_common::topology::Topology;
+use serde_json::Value;
+use std::env;
+use std::time::Duration;
+
+use crate::delay::MAX_ACK_PENDING;
+use crate::failure::{settle, MessageError, TransientFailures};
+use crate::shutdown::Shutdown;
+use crate::utils::ensure_stream;
+
+/// Marks the event the releaser let through as the probe of a half-open circuit.
+pub const CIRCUIT_PROBE_HEADER: &str = "Circuit-Probe";
+// Parked events are checked at least this often, so a reset from the web UI releases them soon
+const MAX_HOLD_RECHECK: Duration = Duration::from_secs(30);
+const MIN_HOLD_RECHECK: Duration = Duration::from_secs(1);
+// Attempts to count a failure when other replicas update the same circuit at once
+const MAX_UPDATE_ATTEMPTS: usize = 5;
+
+#[derive(Clone)]
+pub struct CircuitConfig {
+    /// Consecutive failures of a function that open its circuit
+    pub failure_threshold: u32,
+    /// How long an open circuit parks events before one probe invocation is let through
+    pub cooldown: Duration,
+}
+
+impl CircuitConfig {
+    pub fn from_env() -> Self {
+        let failure_threshold = env::var("CIRCUIT_FAILURE_THRESHOLD")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(5)
+            .max(1);
+        let cooldown_secs = env::var("CIRCUIT_COOLDOWN_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(300);
+
+        CircuitConfig {
+            failure_threshold,
+            cooldown: Duration::from_secs(cooldown_secs),
+        }
+    }
+}
+
+/// The circuits in the KV bucket. A circuit that cannot be read or written never stops an
+/// invocation: the breaker fails open and the error is logged.
+#[derive(Clone)]
+pub struct Circuits {
+    store: kv::Store,
+    config: CircuitConfig,
+}
+
+impl Circuits {
+    pub async fn open(js: &jetstream::Context, topology: &Topology, config: CircuitConfig) -> Result<Self> {
+        let store = js
+            .create_or_update_key_value(kv::Config {
+                bucket: topology.circuit_bucket.clone(),
+                history: 1,
+                ..Default::default()
+            })
+            .await?;
+        Ok(Circuits { store, config })
+    }
+
+    fn cooldown(&self) -> TimeDelta {
+        TimeDelta::from_std(self.config.cooldown).unwrap_or(TimeDelta::MAX)
+    }
+
+    async fn load(&self, lambda_arn: &str) -> Result<Option<(Circuit, u64)>> {
+        let entry = self.store.entry(circuit_key(lambda_arn)).await?;
+        Ok(entry
+            .filter(|entry| entry.operation == kv::Operation::Put)
+            .and_then(|entry| Some((serde_json::from_slice(&entry.value).ok()?, entry.revision))))
+    }
+
+    /// Whether the function may be invoked now. Of the invocations asking once the
+    /// cooldown passed, only one, on any replica, is let through as the probe.
+    pub async fn admit(&self, lambda_arn: &str) -> Admission {
+        let (mut circuit, revision) = match self.load(lambda_arn).await {
+            Ok(Some(loaded)) => loaded,
+            Ok(None) => return Admission::Allow,
+            Err(e) => {
+                eprintln!("⚠️ Failed to read circuit of {}: {}", lambda_arn, e);
+                return Admission::Allow;
+            }
+        };
+        let now = Utc::now();
+        match circuit.admission(now) {
+            Admission::Probe => {
+                circuit.start_probe(now, self.cooldown());
+                let value = serde_json::to_vec(&circuit).expect("Circuit is always serializable");
+                match self.store.update(circuit_key(lambda_arn), value.into(), revision).await {
+                    Ok(_) => {
+                        println!("🔌 Circuit of {} half-open, probing", lambda_arn);
+                        Admission::Probe
+                    }
+                    // Another invocation became the probe
+                    Err(e) if e.kind() == kv::UpdateErrorKind::WrongLastRevision => Admission::Hold(self.cooldown()),
+                    Err(e) => {
+                        eprintln!("⚠️ Failed to half-open circuit of {}: {}", lambda_arn, e);
+                        Admission::Allow
+                    }
+                }
+            }
+            admission => admission,
+        }
+    }
+
+    /// Counts a failed invocation of the function.
+    pub async fn record_failure(&self, lambda_arn: &str, reason: &str) {
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            match self.try_record_failure(lambda_arn, reason).await {
+                Ok(true) => return,
+                Ok(false) => continue,
+                Err(e) => {
+                    eprintln!("⚠️ Failed to record failure in circuit of {}: {}", lambda_arn, e);
+                    return;
+                }
+            }
+        }
+        eprintln!("⚠️ Circuit of {} kept changing, failure not recorded", lambda_arn);
+    }
+
+    // Returns false when another replica changed the circuit first
+    async fn try_record_failure(&self, lambda_arn: &str, reason: &str) -> Result<bool> {
+        let key = circuit_key(lambda_arn);
+        let (mut circuit, revision) = match self.load(lambda_arn).await? {
+            Some((circuit, revision)) => (circuit, Some(revision)),
+            None => (Circuit::new(lambda_arn), None),
+        };
+        let opened = circuit.record_failure(reason, Utc::now(), self.config.failure_threshold, self.cooldown());
+        let value = serde_json::to_vec(&circuit)?.into();
+        match revision {
+            Some(revision) => match self.store.update(&key, value, revision).await {
+                Err(e) if e.kind() == kv::UpdateErrorKind::WrongLastRevision => return Ok(false),
+                result => result?,
+            },
+            None => match self.store.create(&key, value).await {
+                Err(e) if e.kind() == kv::CreateErrorKind::AlreadyExists => return Ok(false),
+                result => result?,
+            },
+        };
+        if opened {
+            eprintln!(
+                "🔌 Circuit of {} open after {} failures in a row, parking its events for {:?}",
+                lambda_arn, circuit.consecutive_failures, self.config.cooldown
+            );
+        }
+        Ok(true)
+    }
+
+    /// Closes the function's circuit after a successful invocation.
+    pub async fn record_success(&self, lambda_arn: &str) {
+        let (circuit, revision) = match self.load(lambda_arn).await {
+            Ok(Some(loaded)) => loaded,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("⚠️ Failed to read circuit of {}: {}", lambda_arn, e);
+                return;
+            }
+        };
+        // A failure recorded in the meantime keeps the circuit as it is
+        match self.store.delete_expect_revision(circuit_key(lambda_arn), Some(revision)).await {
+            Ok(()) if circuit.state != CircuitState::Closed => println!("🔌 Circuit of {} closed", lambda_arn),
+            Ok(()) => {}
+            Err(e) => eprintln!("⚠️ Failed to close circuit of {}: {}", lambda_arn, e),
+        }
+    }
+}
+
+/// Consumes the holding stream and republishes each parked event to the event subject
+/// once its function's circuit closes. When the cooldown has passed, one event is let
+/// through as the probe that decides whether the circuit closes.
+pub async fn run_held_event_releaser(
+    client: Client,
+    topology: Topology,
+    circuit_config: CircuitConfig,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let js = jetstream::new(client.clone());
+    let circuits = Circuits::open(&js, &topology, circuit_config).await?;
+
+    let stream = ensure_stream(&js, jetstream::stream::Config {
+        name: topology.holding_stream.clone(),
+        subjects: vec![format!("{}>", topology.holding_subject_prefix)],
+        ..Default::default()
+    })
+    .await?;
+    let consumer = stream
+        .create_consumer(jetstream::consumer::pull::Config {
+            durable_name: Some(topology.holding_durable.clone()),
+            ack_policy: jetstream::consumer::AckPolicy::Explicit,
+            max_ack_pending: MAX_ACK_PENDING,
+            ..Default::default()
+        })
+        .await?;
+
+    let mut messages = consumer.messages().await?;
+    let failures = TransientFailures::default();
+
+    // Stops pulling on shutdown; a message being handled is always finished and acked first
+    while let Some(msg) = shutdown.interruptible(messages.next()).await.flatten() {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("⚠️ Failed to receive a held event: {}", e);
+                continue;
+            }
+        };
+        let result = release(&js, &circuits, &topology, &msg).await;
+        settle(&js, &topology, &failures, &msg, result).await?;
+    }
+
+    if shutdown.is_requested() {
+        println!("⏹️ Held event releaser stopped");
+    }
+    Ok(())
+}
+
+async fn release(js: &jetstream::Context, circuits: &Circuits, topology: &Topology, msg: &jetstream::Message) -> Result<(), MessageError> {
+    let payload: Value = serde_json::from_slice(&msg.payload)
+        .map_err(|e| MessageError::poison(format!("Held event is not valid JSON: {}", e)))?;
+    let lambda_arn = payload["lambda_arn"]
+        .as_str()
+        .ok_or_else(|| MessageError::poison("Held event has no lambda_arn"))?;
+
+    let mut headers = HeaderMap::new();
+    match circuits.admit(lambda_arn).await {
+        Admission::Allow => {}
+        Admission::Probe => headers.insert(CIRCUIT_PROBE_HEADER, "true"),
+        Admission::Hold(wait) => {
+            let wait = wait.to_std().unwrap_or_default().clamp(MIN_HOLD_RECHECK, MAX_HOLD_RECHECK);
+            return Err(MessageError::Busy(wait));
+        }
+    }
+
+    // Acked only once the event stream stored the released event. The event keeps its envelope;
+    // the message id is derived from the held message, so a release published again is dropped
+    let held_sequence = msg.info().map(|info| info.stream_sequence).unwrap_or_default();
+    let msg_id = republish_msg_id(
+        payload["event_id"].as_str().unwrap_or_default(),
+        payload["target_id"].as_str().unwrap_or_default(),
+        &format!("release-{}", held_sequence),
+    );
+    headers.insert(MSG_ID_HEADER, msg_id.as_str());
+    js.publish_with_headers(topology.event_subject.clone(), headers, msg.payload.clone())
+        .await?
+        .await?;
+    println!("▶️ Released held event {} for {}", payload["event_id"].as_str().unwrap_or(""), lambda_arn);
+    Ok(())
+}
@@ -0,0 +1,68 @@
+// completion.rs
+use async_nats::{jetstream, Client, HeaderMap};
+use anyhow::Result;
+use chrono::Utc;
+use nats_common::envelope::{derived_id, republish_msg_id, Envelope, MSG_ID_HEADER};
+use nats_common::topology::Topology;
+use serde_json::{json, Value};
+
+// Envelope `source` of completion events
+const COMPLETION_SOURCE: &str = "nats_consumer.completion";
+
+/// Publishes a completion event for a successful invocation to the `result_subject`
+/// carried in the status payload, if the rule configured one. The event's `event_type`
+/// is the subject itself, so a rule for e.g. `lambda.result.order.created` matches it.
+/// Subjects under the topology's result subject prefix are captured by the event stream,
+/// so rules can trigger on completion events and chain Lambdas into multi-step pipelines.
+pub async fn publish_completion(
+    client: &Client,
+    js: &jetstream::Context,
+    topology: &Topology,
+    tracked: &Value,
+    duration_ms: Option<f64>,
+    response: Option<&[u8]>,
+) -> Result<()> {
+    let Some(subject) = tracked.get("result_subject").and_then(Value::as_str) else {
+        return Ok(());
+    };
+
+    // Responses that are not JSON are passed on as a string
+    let response = response.map(|bytes| {
+        serde_json::from_slice(bytes).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(bytes).into_owned()))
+    });
+
+    let mut completion = json!({
+        "event_type": subject,
+        "source_event_id": tracked["event_id"],
+        "source_rule": tracked["rule"],
+        "target_arn": tracked["lambda_arn"],
+        "lambda_request_id": tracked["lambda_request_id"],
+        "duration_ms": duration_ms,
+        "response": response,
+        "completed_at": Utc::now().to_rfc3339(),
+    });
+
+    // Derived from the attempt, so publishing the completion again after a lost ack is deduplicated
+    let kind = format!("completion-{}", tracked["retry_index"].as_u64().unwrap_or(0));
+    let envelope = Envelope {
+        id: derived_id(&republish_msg_id(
+            tracked["event_id"].as_str().unwrap_or_default(),
+            tracked["target_id"].as_str().unwrap_or_default(),
+            &kind,
+        )),
+        ..Envelope::new(COMPLETION_SOURCE, subject)
+    };
+    envelope.apply(&mut completion);
+    let mut headers = HeaderMap::new();
+    headers.insert(MSG_ID_HEADER, envelope.id.as_str());
+
+    // The event stream acks the completion events it captures, so none is lost once the
+    // caller acks its message; other subjects have no stream to wait for
+    let completion = serde_json::to_vec(&completion)?;
+    if topology.is_result_subject(subject) {
+        js.publish_with_headers(subject.to_string(), headers, completion.into()).await?.await?;
+    } else {
+        client.publish_with_headers(subject.to_string(), headers, completion.into()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,64 @@
+// concurrency.rs
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps concurrent invocations per Lambda function, so a slow function cannot take
+/// up every worker of the trigger.
+pub struct TargetLimits {
+    per_target: usize,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl TargetLimits {
+    pub fn new(per_target: usize) -> Self {
+        TargetLimits {
+            per_target,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// One permit per function in `lambda_arns`, held for the invocations of one message,
+    /// or `None` if any of them is at its limit. A function listed more than once, e.g. the
+    /// target of two rules, takes one permit, or a limit of 1 could never be met.
+    pub fn try_acquire<'a>(&self, lambda_arns: impl IntoIterator<Item = &'a str>) -> Option<Vec<OwnedSemaphorePermit>> {
+        let lambda_arns: HashSet<&str> = lambda_arns.into_iter().collect();
+        let mut semaphores = self.semaphores.lock().unwrap();
+        lambda_arns
+            .into_iter()
+            .map(|arn| {
+                semaphores
+                    .entry(arn.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(self.per_target)))
+                    .clone()
+                    .try_acquire_owned()
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_busy_target_does_not_hold_back_others() {
+        let limits = TargetLimits::new(1);
+        let slow = limits.try_acquire(["slow"]).unwrap();
+        assert!(limits.try_acquire(["slow"]).is_none());
+        assert!(limits.try_acquire(["fast", "slow"]).is_none());
+        assert_eq!(limits.try_acquire(["fast"]).map(|permits| permits.len()), Some(1));
+
+        drop(slow);
+        assert!(limits.try_acquire(["slow"]).is_some());
+    }
+
+    #[test]
+    fn a_function_targeted_twice_takes_one_permit() {
+        let limits = TargetLimits::new(1);
+        let permits = limits.try_acquire(["shared", "shared"]).unwrap();
+        assert_eq!(permits.len(), 1);
+        assert!(limits.try_acquire(["shared"]).is_none());
+    }
+}
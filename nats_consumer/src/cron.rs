@@ -1,57 +1,235 @@
-use async_nats::{Client, jetstream::{self, consumer::pull::Messages}, HeaderMap};
-use aws_sdk_lambda::Client as LambdaClient;
+// cron.rs
+use async_nats::jetstream::{self, kv, Context};
+use async_nats::{Client, HeaderMap};
+use chrono::{DateTime, TimeDelta, Utc};
+use nats_common::envelope::{derived_id, Envelope, MSG_ID_HEADER};
+use nats_common::schedule::{load_schedules, save_schedule, Schedule};
+use nats_common::topology::Topology;
 use serde_json::Value;
-use anyhow::Result;
+use sled::Db;
+use std::collections::HashMap;
 use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time::sleep;
 
-pub async fn run_status_checker(nats_client: Client, lambda_client: LambdaClient) -> Result<()> {
-    // Setup JetStream context
-    let js = jetstream::new(nats_client);
+use crate::shutdown::Shutdown;
 
-    // Ensure consumer exists or bind to existing one
-    let stream_name = "MY_STREAM";
-    let consumer_name = "status_checker";
+const TICK: Duration = Duration::from_secs(1);
+// Last fire time per schedule id, shared by all replicas so a new leader knows what already ran
+const SCHEDULE_RUNS_BUCKET: &str = "schedule_runs";
+// Envelope `source` of scheduled events
+const SCHEDULER_SOURCE: &str = "nats_consumer.scheduler";
 
-    let consumer = js
-        .get_stream(stream_name)
-        .await?
-        .get_consumer(consumer_name)
+struct ScheduledEntry {
+    schedule: Schedule,
+    next_fire: Option<DateTime<Utc>>,
+    // Random delay within the schedule's flexible time window, picked per run
+    window_offset: TimeDelta,
+}
+
+/// Fires due schedules while this replica holds the scheduler lease (see `leader.rs`).
+pub async fn run_scheduler(
+    client: Client,
+    db: Db,
+    topology: Topology,
+    mut is_leader: watch::Receiver<bool>,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let js = jetstream::new(client);
+    let runs = js
+        .create_or_update_key_value(kv::Config {
+            bucket: SCHEDULE_RUNS_BUCKET.to_string(),
+            history: 1,
+            ..Default::default()
+        })
         .await?;
+    let mut entries: HashMap<String, ScheduledEntry> = HashMap::new();
+    let mut leading = false;
+
+    println!("✅ Scheduler started, waiting for leadership");
+
+    loop {
+        if !*is_leader.borrow_and_update() {
+            if leading {
+                println!("⏸️ Scheduler paused, no longer the leader");
+                entries.clear();
+                leading = false;
+            }
+            match shutdown.interruptible(is_leader.changed()).await {
+                Some(changed) => changed?,
+                None => break,
+            }
+            continue;
+        }
 
-    let mut messages: Messages = consumer.messages().await?;
+        if !leading {
+            // Covers downtime as well as the gap between the old leader's lease expiring and this takeover
+            println!("▶️ Scheduler running as leader");
+            catch_up_missed_runs(&js, &topology, &runs, &db).await?;
+            leading = true;
+        }
 
-    while let Some(msg) = messages.next().await {
-        let payload: Value = serde_json::from_slice(&msg.payload)?;
+        let now = Utc::now();
+        reload_schedules(&db, &mut entries, now);
 
-        let retry_index = payload["retry_index"].as_u64().unwrap_or(0);
-        let event_id = payload["event_id"].as_str().unwrap_or("");
-        let lambda_arn = payload["lambda_arn"].as_str().unwrap();
+        for (id, entry) in entries.iter_mut() {
+            let Some(scheduled_time) = entry.next_fire.filter(|next| *next + entry.window_offset <= now) else {
+                continue;
+            };
 
-        // [SIMPLIFIED] Check Lambda logs or state externally – here we assume failed
-        let lambda_failed = true;
+            let schedule = &entry.schedule;
+            let mut payload = schedule.event_payload(scheduled_time, now);
+            if schedule.flexible_window_minutes.is_some() {
+                payload["window_offset_seconds"] = entry.window_offset.num_seconds().into();
+            }
 
-        if lambda_failed && retry_index < 6 {
-            let mut retry_payload = payload.clone();
-            retry_payload["retry_index"] = (retry_index + 1).into();
+            match fire(&js, &topology, &runs, &db, schedule, scheduled_time, payload).await {
+                Ok(()) => println!("⏰ Fired schedule {} ({}, {})", id, schedule.event_type, schedule.expression),
+                Err(e) => {
+                    // Keep next_fire as is so the publish is retried on the next tick
+                    eprintln!("❌ Failed to publish schedule {}: {:?}", id, e);
+                    continue;
+                }
+            }
 
-            let delay = get_delay_seconds(retry_index + 1);
+            if schedule.parsed_expression().is_ok_and(|e| e.is_one_time()) {
+                entry.next_fire = None;
+                continue;
+            }
 
-            let mut headers = HeaderMap::new();
-            headers.insert("Nats-Delay".into(), format!("{}s", delay).into());
-            headers.insert("Nats-Msg-Id".into(), event_id.into());
+            // Searching from the scheduled time keeps a run due inside the window from being skipped
+            entry.next_fire = schedule.next_fire(scheduled_time).unwrap_or_else(|e| {
+                eprintln!("❌ Schedule {}: {}", id, e);
+                None
+            });
+            entry.window_offset = pick_window_offset(schedule);
+        }
 
-            js.publish_with_headers("my.event".into(), headers, serde_json::to_vec(&retry_payload)?)
-                .await?;
+        if shutdown.interruptible(sleep(TICK)).await.is_none() {
+            break;
         }
+    }
+
+    println!("⏹️ Scheduler stopped");
+    Ok(())
+}
+
+/// Applies each schedule's catch-up policy to runs that came due while the consumer was down.
+async fn catch_up_missed_runs(js: &Context, topology: &Topology, runs: &kv::Store, db: &Db) -> Result<(), Box<dyn std::error::Error>> {
+    let now = Utc::now();
+
+    for schedule in load_schedules(db).into_iter().filter(|s| s.enabled) {
+        // A schedule that never fired catches up from when it was created or last changed
+        let Some(baseline) = schedule.catch_up_baseline(get_last_fire(runs, &schedule.id).await?) else {
+            continue;
+        };
+
+        let missed = match schedule.missed_runs(baseline, now) {
+            Ok(missed) => missed,
+            Err(e) => {
+                eprintln!("❌ Schedule {}: {}", schedule.id, e);
+                continue;
+            }
+        };
 
-        msg.ack().await?;
+        for scheduled_time in missed {
+            // Catch-up runs are already late, so they go out right away without a window offset
+            let mut payload = schedule.event_payload(scheduled_time, now);
+            payload["catch_up"] = true.into();
+            fire(js, topology, runs, db, &schedule, scheduled_time, payload).await?;
+            println!("⏰ Caught up schedule {} run due at {}", schedule.id, scheduled_time);
+        }
     }
 
     Ok(())
 }
 
-fn get_delay_seconds(index: u64) -> u64 {
-    let delays = [60, 600, 1800, 3600, 14400, 28800, 86400];
-    *delays.get(index as usize).unwrap_or(&86400)
+/// Publishes one run of a schedule and records it as the schedule's last fire time.
+/// One-time schedules are disabled once they have fired. The event id is derived from the
+/// schedule and run, so a run published twice, e.g. by two leaders, is deduplicated.
+async fn fire(
+    js: &Context,
+    topology: &Topology,
+    runs: &kv::Store,
+    db: &Db,
+    schedule: &Schedule,
+    scheduled_time: DateTime<Utc>,
+    mut payload: Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let envelope = Envelope {
+        id: derived_id(&format!("{}@{}", schedule.id, scheduled_time.to_rfc3339())),
+        ..Envelope::new(SCHEDULER_SOURCE, &schedule.event_type)
+    };
+    envelope.apply(&mut payload);
+    let mut headers = HeaderMap::new();
+    headers.insert(MSG_ID_HEADER, envelope.id.as_str());
+
+    js.publish_with_headers(topology.event_subject.clone(), headers, serde_json::to_vec(&payload)?.into())
+        .await?
+        .await?;
+
+    // The event is out; a failed bookkeeping write must not make the caller publish it again
+    if let Err(e) = runs
+        .put(&schedule.id, scheduled_time.to_rfc3339().into())
+        .await
+    {
+        eprintln!("❌ Failed to record last fire of schedule {}: {}", schedule.id, e);
+    }
+
+    if schedule.parsed_expression().is_ok_and(|e| e.is_one_time()) {
+        let mut completed = schedule.clone();
+        completed.enabled = false;
+        save_schedule(db, &completed)?;
+        println!("✅ One-time schedule {} completed and disabled", schedule.id);
+    }
+
+    Ok(())
+}
+
+/// Picks how long after the scheduled time a run is published, uniformly within the
+/// schedule's flexible time window, so schedules sharing an expression don't all fire at once.
+fn pick_window_offset(schedule: &Schedule) -> TimeDelta {
+    match schedule.flexible_window_minutes {
+        Some(window) if window > 0 => TimeDelta::seconds(rand::random_range(0..i64::from(window) * 60)),
+        _ => TimeDelta::zero(),
+    }
+}
+
+/// Scheduled time of the last run that was published for a schedule.
+async fn get_last_fire(runs: &kv::Store, id: &str) -> Result<Option<DateTime<Utc>>, Box<dyn std::error::Error>> {
+    Ok(runs
+        .get(id)
+        .await?
+        .and_then(|value| String::from_utf8(value.to_vec()).ok())
+        .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+        .map(|last| last.with_timezone(&Utc)))
+}
+
+/// Syncs the in-memory schedule table with the schedules stored in sled so that
+/// schedules added, edited, paused or removed in the web UI are picked up without a restart.
+fn reload_schedules(db: &Db, entries: &mut HashMap<String, ScheduledEntry>, now: DateTime<Utc>) {
+    let schedules: Vec<Schedule> = load_schedules(db).into_iter().filter(|s| s.enabled).collect();
+
+    entries.retain(|id, _| schedules.iter().any(|s| &s.id == id));
+
+    for schedule in schedules {
+        if entries.get(&schedule.id).is_some_and(|e| e.schedule == schedule) {
+            continue;
+        }
+
+        match schedule.next_fire(now) {
+            Ok(next_fire) => {
+                match next_fire {
+                    Some(next) => println!("✅ Loaded schedule {} ({}), next at {}", schedule.id, schedule.expression, next),
+                    None => println!("⚠️ Schedule {} ({}) has no upcoming runs", schedule.id, schedule.expression),
+                }
+                let window_offset = pick_window_offset(&schedule);
+                entries.insert(schedule.id.clone(), ScheduledEntry { schedule, next_fire, window_offset });
+            }
+            Err(e) => {
+                eprintln!("❌ Schedule {}: {}", schedule.id, e);
+                entries.remove(&schedule.id);
+            }
+        }
+    }
 }
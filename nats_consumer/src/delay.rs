@@ -0,0 +1,40 @@
+// delay.rs
+// Delayed delivery on stock JetStream. A delayed message is published right away with a
+// `Due-At` header; a consumer that receives it early naks it with the remaining time, and
+// the server redelivers it once that time has passed.
+use async_nats::jetstream::{self, AckKind};
+use async_nats::HeaderMap;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::time::Duration;
+
+pub const DUE_AT_HEADER: &str = "Due-At";
+/// Deferred messages stay pending on the consumer until they are due, so the limit
+/// is well above the number of retries and status checks expected to be waiting.
+pub const MAX_ACK_PENDING: i64 = 100_000;
+
+/// Headers that hold a message back for `delay_secs`.
+pub fn delay_headers(delay_secs: u64) -> HeaderMap {
+    let due_at = Utc::now() + TimeDelta::seconds(delay_secs as i64);
+    let mut headers = HeaderMap::new();
+    headers.insert(DUE_AT_HEADER, due_at.to_rfc3339().as_str());
+    headers
+}
+
+/// How long until the message is due, or `None` if it is due now or has no `Due-At` header.
+pub fn remaining_delay(msg: &jetstream::Message) -> Option<Duration> {
+    let due_at = msg.headers.as_ref()?.get(DUE_AT_HEADER)?;
+    let due_at = DateTime::parse_from_rfc3339(due_at.as_str()).ok()?;
+    (due_at.with_timezone(&Utc) - Utc::now()).to_std().ok().filter(|d| !d.is_zero())
+}
+
+/// Naks a message that is not due yet so JetStream redelivers it when it is.
+/// Returns `true` if the message was deferred and must not be processed now.
+pub async fn defer_until_due(msg: &jetstream::Message) -> anyhow::Result<bool> {
+    match remaining_delay(msg) {
+        Some(delay) => {
+            msg.ack_with(AckKind::Nak(Some(delay))).await.map_err(|e| anyhow::anyhow!(e))?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
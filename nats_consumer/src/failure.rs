@@ -0,0 +1,182 @@
+// failure.rs
+// How a consumer settles a message it failed to process, so one bad message or a
+// passing outage does not stop the consumer loop.
+use async_nats::jetstream::{self, AckKind};
+use chrono::Utc;
+use nats_common::dead_letter::DeadLetter;
+use nats_common::envelope;
+use nats_common::topology::Topology;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A transient failure that keeps coming back is treated as poison after this many failures
+const MAX_TRANSIENT_FAILURES: u32 = 20;
+// Failure counts of messages that were not settled here again, e.g. acked by another replica, are dropped after this long
+const FAILURE_COUNT_TTL: Duration = Duration::from_secs(86400);
+const MAX_NAK_DELAY: Duration = Duration::from_secs(300);
+const MAX_THROTTLE_DELAY: Duration = Duration::from_secs(60);
+
+pub enum MessageError {
+    /// The message can never be processed, e.g. malformed JSON: terminated and dead-lettered
+    Poison(String),
+    /// Processing may succeed later, e.g. NATS or CloudWatch is unavailable: redelivered with backoff
+    Transient(anyhow::Error),
+    /// The consumer cannot go on, e.g. the sled DB failed: the task stops and is restarted
+    Fatal(anyhow::Error),
+    /// A function the message goes to is at its concurrency or rate limit: redelivered after the delay
+    Busy(Duration),
+    /// Lambda throttled the invocation: redelivered with backoff, not counted as a failed attempt
+    Throttled,
+}
+
+impl MessageError {
+    pub fn poison(reason: impl Display) -> Self {
+        MessageError::Poison(reason.to_string())
+    }
+
+    pub fn fatal(error: impl Into<anyhow::Error>) -> Self {
+        MessageError::Fatal(error.into())
+    }
+}
+
+// `?` treats errors as transient unless they are classified otherwise
+impl<E: Into<anyhow::Error>> From<E> for MessageError {
+    fn from(error: E) -> Self {
+        MessageError::Transient(error.into())
+    }
+}
+
+/// Transient failures of the messages a consumer handled, by stream and sequence. JetStream's
+/// delivery count cannot be used, as it also counts redeliveries of busy, throttled and
+/// deferred messages. Each replica counts the failures it saw.
+#[derive(Default)]
+pub struct TransientFailures {
+    counts: Mutex<HashMap<(String, u64), (u32, Instant)>>,
+}
+
+impl TransientFailures {
+    /// Counts a failure of `msg` and returns how many it has had.
+    fn record(&self, msg: &jetstream::Message) -> u32 {
+        let Ok(info) = msg.info() else {
+            return 1;
+        };
+        let mut counts = self.counts.lock().unwrap();
+        counts.retain(|_, (_, last_failure)| last_failure.elapsed() < FAILURE_COUNT_TTL);
+        let (failures, last_failure) = counts
+            .entry((info.stream.to_string(), info.stream_sequence))
+            .or_insert((0, Instant::now()));
+        *failures += 1;
+        *last_failure = Instant::now();
+        *failures
+    }
+
+    fn forget(&self, msg: &jetstream::Message) {
+        if let Ok(info) = msg.info() {
+            self.counts.lock().unwrap().remove(&(info.stream.to_string(), info.stream_sequence));
+        }
+    }
+}
+
+/// Acks, naks or terminates the message depending on how processing went.
+/// Returns an error only for fatal failures, after which the consumer should stop.
+pub async fn settle(
+    js: &jetstream::Context,
+    topology: &Topology,
+    failures: &TransientFailures,
+    msg: &jetstream::Message,
+    result: Result<(), MessageError>,
+) -> anyhow::Result<()> {
+    let delivered = msg.info().map(|info| info.delivered).unwrap_or(1);
+    let failure_count = match &result {
+        Err(MessageError::Transient(_)) => failures.record(msg),
+        _ => 0,
+    };
+    let result = match result {
+        Err(MessageError::Transient(e)) if failure_count >= MAX_TRANSIENT_FAILURES => {
+            Err(MessageError::poison(format!("{} (gave up after {} failures)", e, failure_count)))
+        }
+        result => result,
+    };
+
+    match result {
+        Ok(()) => {
+            failures.forget(msg);
+            if let Err(e) = msg.ack().await {
+                eprintln!("⚠️ Failed to ack message on {}: {}", msg.subject, e);
+            }
+        }
+        Err(MessageError::Transient(e)) => {
+            let delay = Duration::from_secs(2u64.saturating_pow(failure_count.min(16))).min(MAX_NAK_DELAY);
+            eprintln!("⚠️ Message on {} failed ({}), redelivering in {:?}", msg.subject, e, delay);
+            if let Err(e) = msg.ack_with(AckKind::Nak(Some(delay))).await {
+                eprintln!("⚠️ Failed to nak message on {}: {}", msg.subject, e);
+            }
+        }
+        Err(MessageError::Busy(delay)) => {
+            if let Err(e) = msg.ack_with(AckKind::Nak(Some(delay))).await {
+                eprintln!("⚠️ Failed to nak message on {}: {}", msg.subject, e);
+            }
+        }
+        Err(MessageError::Throttled) => {
+            let delay = throttle_delay(delivered);
+            eprintln!("⏸️ Lambda throttled message on {}, redelivering in {:?}", msg.subject, delay);
+            if let Err(e) = msg.ack_with(AckKind::Nak(Some(delay))).await {
+                eprintln!("⚠️ Failed to nak message on {}: {}", msg.subject, e);
+            }
+        }
+        Err(MessageError::Poison(reason)) => {
+            eprintln!("❌ Message on {} cannot be processed ({}), moving it to the dead-letter stream", msg.subject, reason);
+            failures.forget(msg);
+            let dead_letter = poison_dead_letter(msg, reason);
+            let published = match js
+                .publish(topology.dead_letter_subject.clone(), serde_json::to_vec(&dead_letter)?.into())
+                .await
+            {
+                Ok(ack) => ack.await.map(|_| ()).map_err(anyhow::Error::from),
+                Err(e) => Err(e.into()),
+            };
+            // Never drop a message that did not make it to the dead-letter stream
+            let ack_kind = match published {
+                Ok(()) => AckKind::Term,
+                Err(e) => {
+                    eprintln!("⚠️ Failed to dead-letter message on {}: {}", msg.subject, e);
+                    AckKind::Nak(Some(MAX_NAK_DELAY))
+                }
+            };
+            if let Err(e) = msg.ack_with(ack_kind).await {
+                eprintln!("⚠️ Failed to settle message on {}: {}", msg.subject, e);
+            }
+        }
+        Err(MessageError::Fatal(e)) => {
+            // Hand the message to another replica or to the restarted consumer
+            let _ = msg.ack_with(AckKind::Nak(None)).await;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Backoff after Lambda throttled an invocation of the `delivered`th delivery: 2^delivered
+/// seconds up to a minute, with jitter so throttled messages do not all return at once.
+pub fn throttle_delay(delivered: i64) -> Duration {
+    let delay = Duration::from_secs(2u64.saturating_pow(delivered.clamp(0, 16) as u32)).min(MAX_THROTTLE_DELAY);
+    delay.mul_f64(0.5 + rand::random::<f64>() / 2.0)
+}
+
+// Dead-letter record for a message that may not even be JSON
+fn poison_dead_letter(msg: &jetstream::Message, reason: String) -> DeadLetter {
+    let payload = serde_json::from_slice(&msg.payload)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&msg.payload).into_owned()));
+    let mut dead_letter = DeadLetter::from_status_payload(&payload, reason, Utc::now().to_rfc3339());
+    if dead_letter.event_id.is_empty() {
+        dead_letter.event_id = match (envelope::event_id(&payload), msg.info()) {
+            (Some(id), _) => id.to_string(),
+            (None, Ok(info)) => format!("{}-{}", info.stream, info.stream_sequence),
+            (None, Err(_)) => String::new(),
+        };
+    }
+    dead_letter
+}
@@ -0,0 +1,59 @@
+// idempotency.rs
+// Guards against invoking a target twice for one message. JetStream redelivers a message
+// whose ack was lost, e.g. when a replica crashed after invoking its targets, so every
+// handled attempt is recorded in a KV bucket shared by all replicas.
+use async_nats::jetstream::{self, kv};
+use anyhow::Result;
+use chrono::Utc;
+use nats_common::rule::Target;
+use std::time::Duration;
+
+const INVOCATION_BUCKET: &str = "lambda_invocations";
+
+/// Identifies one attempt of one target: the message's stream sequence and publish time,
+/// which tells it apart from a message with the same sequence in a recreated stream, plus
+/// the target. Also passed to the function as its idempotency key.
+pub fn attempt_key(msg: &jetstream::Message, target: &Target) -> Option<String> {
+    let info = msg.info().ok()?;
+    Some(format!("{}-{}-{}", info.stream_sequence, info.published.unix_timestamp_nanos(), target.id))
+}
+
+/// Attempts handled within the TTL. The guard fails open: when the bucket cannot be read
+/// the target is invoked, since a duplicate invocation beats a lost one.
+#[derive(Clone)]
+pub struct Invocations {
+    store: kv::Store,
+}
+
+impl Invocations {
+    pub async fn open(js: &jetstream::Context, ttl: Duration) -> Result<Self> {
+        let store = js
+            .create_or_update_key_value(kv::Config {
+                bucket: INVOCATION_BUCKET.to_string(),
+                history: 1,
+                max_age: ttl,
+                ..Default::default()
+            })
+            .await?;
+        Ok(Invocations { store })
+    }
+
+    /// When the attempt was handled, if it was.
+    pub async fn handled_at(&self, attempt_key: &str) -> Option<String> {
+        match self.store.get(attempt_key).await {
+            Ok(value) => value.map(|v| String::from_utf8_lossy(&v).into_owned()),
+            Err(e) => {
+                eprintln!("⚠️ Failed to look up attempt {}: {}", attempt_key, e);
+                None
+            }
+        }
+    }
+
+    /// Records that the attempt was handled: the function was invoked, or the event parked,
+    /// deferred or sent to its retry chain.
+    pub async fn record(&self, attempt_key: &str) {
+        if let Err(e) = self.store.put(attempt_key, Utc::now().to_rfc3339().into()).await {
+            eprintln!("⚠️ Failed to record attempt {}: {}", attempt_key, e);
+        }
+    }
+}
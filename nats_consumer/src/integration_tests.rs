@@ -0,0 +1,292 @@
+// integration_tests.rs
+// Runs the trigger and status checker against a local nats-server with mocked AWS clients.
+// Ignored by default since they need a nats-server binary (on PATH or named by NATS_SERVER_BIN):
+//   cargo test -p nats_consumer -- --ignored
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_nats::jetstream;
+use aws_credential_types::Credentials;
+use aws_sdk_lambda::config::{BehaviorVersion, Region};
+use aws_smithy_http_client::test_util::infallible_client_fn;
+use aws_smithy_types::body::SdkBody;
+use futures::StreamExt;
+use nats_common::dead_letter::DeadLetter;
+use nats_common::retry_policy::RetryPolicy;
+use nats_common::rule::{save_rule, DeliveryFormat, InvocationMode, Rule, Target};
+use nats_common::topology::Topology;
+use serde_json::{json, Value};
+
+use crate::circuit::CircuitConfig;
+use crate::lambda_trigger::{run_lambda_trigger, TriggerConfig};
+use crate::shutdown::Shutdown;
+use crate::status_checker::run_status_checker;
+
+struct NatsServer {
+    child: Child,
+    store_dir: PathBuf,
+    url: String,
+}
+
+impl NatsServer {
+    /// Starts nats-server with JetStream on a free port.
+    fn start() -> Self {
+        let bin = std::env::var("NATS_SERVER_BIN").unwrap_or_else(|_| "nats-server".to_string());
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let store_dir = std::env::temp_dir().join(format!("nats_consumer_test_{}", port));
+        let child = Command::new(&bin)
+            .args(["-js", "-a", "127.0.0.1", "-p", &port.to_string(), "-sd"])
+            .arg(&store_dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to start {} ({}): put nats-server on PATH or set NATS_SERVER_BIN", bin, e));
+        NatsServer {
+            child,
+            store_dir,
+            url: format!("nats://127.0.0.1:{}", port),
+        }
+    }
+
+    async fn connect(&self) -> async_nats::Client {
+        for _ in 0..50 {
+            if let Ok(client) = async_nats::connect(&self.url).await {
+                return client;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("nats-server did not accept connections at {}", self.url);
+    }
+}
+
+impl Drop for NatsServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.store_dir);
+    }
+}
+
+const LAMBDA_ARN: &str = "arn:aws:lambda:us-east-1:123456789012:function:orders";
+
+// Accepts every asynchronous invocation with a new request id
+fn mocked_lambda(invocations: Arc<AtomicUsize>) -> aws_sdk_lambda::Client {
+    let http_client = infallible_client_fn(move |_request| {
+        let n = invocations.fetch_add(1, Ordering::SeqCst) + 1;
+        http::Response::builder()
+            .status(202)
+            .header("x-amzn-RequestId", format!("request-{}", n))
+            .body(SdkBody::empty())
+            .unwrap()
+    });
+    let config = aws_sdk_lambda::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .http_client(http_client)
+        .build();
+    aws_sdk_lambda::Client::from_conf(config)
+}
+
+// Reports every invocation as failed: answers the query for a request id with the lines
+// Lambda logs for an invocation that threw
+fn failing_logs() -> aws_sdk_cloudwatchlogs::Client {
+    let http_client = infallible_client_fn(|request| {
+        let query: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+        let request_id = query["filterPattern"].as_str().unwrap().trim_matches('"').to_string();
+        let lines = [
+            format!("START RequestId: {} Version: $LATEST", request_id),
+            format!("2025-01-01T00:00:00.000Z\t{}\tERROR\tInvoke Error \tboom", request_id),
+            format!("END RequestId: {}", request_id),
+            format!("REPORT RequestId: {}\tDuration: 12.00 ms\tBilled Duration: 13 ms", request_id),
+        ];
+        let events: Vec<Value> = lines.iter().map(|line| json!({"message": line})).collect();
+        http::Response::builder()
+            .status(200)
+            .body(SdkBody::from(json!({"events": events}).to_string()))
+            .unwrap()
+    });
+    let config = aws_sdk_cloudwatchlogs::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .http_client(http_client)
+        .build();
+    aws_sdk_cloudwatchlogs::Client::from_conf(config)
+}
+
+fn failing_rule(max_attempts: u32) -> Rule {
+    Rule {
+        event_type: "order.created".to_string(),
+        targets: vec![Target {
+            id: "1".to_string(),
+            lambda_arn: LAMBDA_ARN.to_string(),
+            input: None,
+        }],
+        pattern: None,
+        invocation_mode: InvocationMode::Event,
+        reply_subject: None,
+        result_subject: None,
+        retry_policy: RetryPolicy {
+            max_attempts,
+            base_delay_secs: 1,
+            max_delay_secs: 1,
+            delays_secs: Vec::new(),
+            ..RetryPolicy::default()
+        },
+        rate_limit: None,
+        delivery_format: DeliveryFormat::Plain,
+    }
+}
+
+// Waits until both consumers have created their streams
+async fn wait_for_streams(js: &jetstream::Context, topology: &Topology) {
+    while js.get_stream(&topology.event_stream).await.is_err() || js.get_stream(&topology.status_stream).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+async fn next_json(subscriber: &mut async_nats::Subscriber) -> Value {
+    let message = subscriber.next().await.expect("subscription closed");
+    serde_json::from_slice(&message.payload).unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs nats-server, run with --ignored"]
+async fn event_flows_from_trigger_to_status_check_to_retry() {
+    let server = NatsServer::start();
+    let client = server.connect().await;
+    let js = jetstream::new(client.clone());
+    let topology = Topology::default();
+    let (_shutdown_tx, shutdown) = Shutdown::new();
+
+    // One retry, one second after the first failure, then the dead-letter stream
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    save_rule(&db, &failing_rule(1)).unwrap();
+
+    let invocations = Arc::new(AtomicUsize::new(0));
+    let lambda_client = mocked_lambda(invocations.clone());
+    let trigger = run_lambda_trigger(
+        client.clone(),
+        lambda_client.clone(),
+        db,
+        topology.clone(),
+        TriggerConfig {
+            status_check_delay_secs: 0,
+            ..TriggerConfig::from_env()
+        },
+        CircuitConfig::from_env(),
+        shutdown.clone(),
+    );
+    let status_checker = run_status_checker(
+        client.clone(),
+        lambda_client,
+        failing_logs(),
+        topology.clone(),
+        CircuitConfig::from_env(),
+        shutdown,
+    );
+
+    let flow = async {
+        let mut status_checks = client.subscribe(topology.status_subject.clone()).await.unwrap();
+        let mut dead_letters = client.subscribe(topology.dead_letter_subject.clone()).await.unwrap();
+        wait_for_streams(&js, &topology).await;
+
+        js.publish(topology.event_subject.clone(), json!({"event_type": "order.created"}).to_string().into())
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let first = next_json(&mut status_checks).await;
+        assert_eq!(first["retry_index"], 0);
+        assert_eq!(first["rule"], "order.created");
+        assert_eq!(first["lambda_request_id"], "request-1");
+
+        // The status checker found the failure and retried the event through the trigger
+        let retry = next_json(&mut status_checks).await;
+        assert_eq!(retry["retry_index"], 1);
+        assert_eq!(retry["event_id"], first["event_id"]);
+        assert_eq!(retry["lambda_request_id"], "request-2");
+
+        let dead_letter: DeadLetter = serde_json::from_value(next_json(&mut dead_letters).await).unwrap();
+        assert_eq!(dead_letter.attempts.len(), 2);
+        assert!(dead_letter.failure_reason.contains("boom"));
+        assert_eq!(dead_letter.payload, json!({"event_type": "order.created"}));
+    };
+
+    tokio::select! {
+        result = trigger => panic!("trigger stopped: {:?}", result.err().map(|e| e.to_string())),
+        result = status_checker => panic!("status checker stopped: {:?}", result.err()),
+        result = tokio::time::timeout(Duration::from_secs(30), flow) => result.expect("event did not complete the flow"),
+    }
+    assert_eq!(invocations.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+#[ignore = "needs nats-server, run with --ignored"]
+async fn malformed_event_is_dead_lettered_and_the_trigger_keeps_running() {
+    let server = NatsServer::start();
+    let client = server.connect().await;
+    let js = jetstream::new(client.clone());
+    let topology = Topology::default();
+    let (_shutdown_tx, shutdown) = Shutdown::new();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    save_rule(&db, &failing_rule(0)).unwrap();
+
+    let lambda_client = mocked_lambda(Arc::new(AtomicUsize::new(0)));
+    let trigger = run_lambda_trigger(
+        client.clone(),
+        lambda_client.clone(),
+        db,
+        topology.clone(),
+        TriggerConfig {
+            status_check_delay_secs: 0,
+            ..TriggerConfig::from_env()
+        },
+        CircuitConfig::from_env(),
+        shutdown.clone(),
+    );
+    let status_checker = run_status_checker(
+        client.clone(),
+        lambda_client,
+        failing_logs(),
+        topology.clone(),
+        CircuitConfig::from_env(),
+        shutdown,
+    );
+
+    let flow = async {
+        let mut status_checks = client.subscribe(topology.status_subject.clone()).await.unwrap();
+        let mut dead_letters = client.subscribe(topology.dead_letter_subject.clone()).await.unwrap();
+        wait_for_streams(&js, &topology).await;
+
+        js.publish(topology.event_subject.clone(), "{not json".into()).await.unwrap().await.unwrap();
+        js.publish(topology.event_subject.clone(), json!({"event_type": "order.created"}).to_string().into())
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let poison: DeadLetter = serde_json::from_value(next_json(&mut dead_letters).await).unwrap();
+        assert!(poison.failure_reason.contains("not valid JSON"));
+        assert_eq!(poison.payload, json!("{not json"));
+
+        // The next event is still processed; with retries disabled its failure is dead-lettered at once
+        let status = next_json(&mut status_checks).await;
+        assert_eq!(status["rule"], "order.created");
+        let failed: DeadLetter = serde_json::from_value(next_json(&mut dead_letters).await).unwrap();
+        assert!(failed.failure_reason.contains("retries exhausted"));
+    };
+
+    tokio::select! {
+        result = trigger => panic!("trigger stopped: {:?}", result.err().map(|e| e.to_string())),
+        result = status_checker => panic!("status checker stopped: {:?}", result.err()),
+        result = tokio::time::timeout(Duration::from_secs(30), flow) => result.expect("events were not processed"),
+    }
+}
@@ -1,118 +1,696 @@
 // lambda_trigger.rs
 use async_nats::{jetstream, Client};
+use aws_sdk_lambda::config::http::HttpResponse;
+use aws_sdk_lambda::error::{DisplayErrorContext, SdkError};
+use aws_sdk_lambda::operation::invoke::InvokeError;
+use aws_sdk_lambda::operation::RequestId;
 use aws_sdk_lambda::primitives::Blob;
-use serde_json::Value;
+use aws_sdk_lambda::types::InvocationType;
+use base64ct::{Base64, Encoding};
+use serde_json::{json, Value};
+use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use anyhow::Result;
+use chrono::Utc;
+use nats_common::circuit::Admission;
+use nats_common::cloud_event;
+use nats_common::dead_letter::Attempt;
+use nats_common::envelope::{self, derived_id, new_id};
+use nats_common::rule::{get_rule, InvocationMode, Rule, RuleCache, Target};
+use nats_common::topology::Topology;
 use sled::Db;
+use std::env;
 
+use crate::circuit::{CircuitConfig, Circuits, CIRCUIT_PROBE_HEADER};
+use crate::concurrency::TargetLimits;
+use crate::delay::{defer_until_due, delay_headers, MAX_ACK_PENDING};
+use crate::completion::publish_completion;
+use crate::failure::{settle, throttle_delay, MessageError, TransientFailures};
+use crate::idempotency::{attempt_key, Invocations};
+use crate::rate_limit::RateLimits;
+use crate::retry::{dead_letter, defer_throttled, retry_or_dead_letter};
+use crate::shutdown::Shutdown;
+use crate::utils::ensure_stream;
+use std::time::{Duration, Instant};
 
+const IDEMPOTENCY_KEY_FIELD: &str = "idempotency_key";
+// CloudEvents extension attribute names are lowercase letters and digits only
+const IDEMPOTENCY_KEY_ATTRIBUTE: &str = "idempotencykey";
+// How soon an event for a function at its concurrency limit is redelivered
+const TARGET_BUSY_DELAY: Duration = Duration::from_secs(1);
+// A worker waits this long for a rate-limited function; events that would wait longer are sent back
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(1);
 
-pub async fn run_lambda_trigger(client: Client, lambda_client: aws_sdk_lambda::Client,db: Db) ->Result<(), Box<dyn std::error::Error>> {
-    // Connect to NATS
-    // let client = async_nats::connect(nats_url).await?;
-    
+#[derive(Clone)]
+pub struct TriggerConfig {
+    /// Time given to CloudWatch Logs to receive the function's REPORT line before its status is checked
+    pub status_check_delay_secs: u64,
+    /// Messages handled at the same time by this replica
+    pub concurrency: usize,
+    /// Invocations of one Lambda function at the same time by this replica
+    pub target_concurrency: usize,
+    /// How long handled attempts are remembered, so a redelivered message does not invoke them again
+    pub invocation_record_ttl: Duration,
+    /// Window in which the event stream drops a message whose `Nats-Msg-Id` it already stored
+    pub dedup_window: Duration,
+}
 
-    let js = jetstream::new(client.clone());
+impl TriggerConfig {
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        }
 
-    let stream = match js.get_stream("my_bridge").await {
-        Ok(s) => {
-            println!("✅ Stream found");
-            s
-        }
-        Err(_e) => {
-           // Make sure your function returns `Box<dyn Error>`
-            match js.create_stream(jetstream::stream::Config {
-                name: "my_bridge".to_string(),
-                subjects: vec!["my.event".to_string()],
-                ..Default::default()
-            }).await {
-                Ok(s) => {
-                    println!("✅ Successfully created stream");
-                    s
-                }
-                Err(e) => {
-                    println!("❌ Failed to create stream: {:?}", e);
-                    return Err(Box::new(e)); // Make sure your function returns `Box<dyn Error>`
-                }
-            }
+        TriggerConfig {
+            status_check_delay_secs: var("STATUS_CHECK_DELAY_SECS", 60),
+            concurrency: var("TRIGGER_CONCURRENCY", 16).max(1),
+            target_concurrency: var("TRIGGER_TARGET_CONCURRENCY", 4).max(1),
+            invocation_record_ttl: Duration::from_secs(var("INVOCATION_RECORD_TTL_SECS", 86400).max(1)),
+            dedup_window: Duration::from_secs(var("EVENT_DEDUP_WINDOW_SECS", 120).max(1)),
         }
+    }
+}
+
+// The event as a JSON object. A binary-mode CloudEvent is turned into its structured form,
+// so its retries and dead letters carry its attributes
+fn parse_event(msg: &jetstream::Message) -> Result<Value, MessageError> {
+    let headers = || {
+        msg.headers
+            .iter()
+            .flat_map(|headers| headers.iter())
+            .flat_map(|(name, values)| values.iter().map(move |value| (name.as_ref(), value.as_str())))
+    };
+    let payload: Value = if cloud_event::is_binary(headers()) {
+        cloud_event::from_binary(headers(), &msg.payload).map_err(MessageError::poison)?
+    } else {
+        serde_json::from_slice(&msg.payload).map_err(|e| MessageError::poison(format!("Event is not valid JSON: {}", e)))?
     };
-    
+    if !payload.is_object() {
+        return Err(MessageError::poison("Event is not a JSON object"));
+    }
+    if cloud_event::is_cloud_event(&payload) {
+        cloud_event::validate(&payload).map_err(MessageError::poison)?;
+    }
+    Ok(payload)
+}
+
+// Same for every delivery of the message, and differs from a message with the same
+// sequence in a recreated stream
+fn message_event_id(msg: &jetstream::Message) -> String {
+    match msg.info() {
+        Ok(info) => derived_id(&format!("{}-{}-{}", info.stream, info.stream_sequence, info.published.unix_timestamp_nanos())),
+        Err(_) => new_id(),
+    }
+}
+
+/// Outcome of invoking one target.
+#[derive(Debug)]
+struct Invocation {
+    request_id: String, // Empty if Lambda returned none
+    // RequestResponse only: set when the function failed, e.g. "Unhandled"
+    function_error: Option<String>,
+    // RequestResponse only: what the function returned, or its error details
+    response: Option<Vec<u8>>,
+    // Time until Lambda answered; the run time of the function itself for RequestResponse
+    duration_ms: f64,
+}
+
+
+
+pub async fn run_lambda_trigger(
+    client: Client,
+    lambda_client: aws_sdk_lambda::Client,
+    db: Db,
+    topology: Topology,
+    config: TriggerConfig,
+    circuit_config: CircuitConfig,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let js = jetstream::new(client.clone());
+    let circuits = Circuits::open(&js, &topology, circuit_config).await?;
+
+    // Completion events are captured too, so rules can trigger on them
+    let stream = ensure_stream(&js, jetstream::stream::Config {
+        name: topology.event_stream.clone(),
+        subjects: topology.event_stream_subjects(),
+        duplicate_window: config.dedup_window,
+        ..Default::default()
+    })
+    .await?;
+
     let consumer = stream
         .create_consumer(jetstream::consumer::pull::Config {
-            durable_name: Some("lambda_trigger".into()),
+            durable_name: Some(topology.trigger_durable.clone()),
             ack_policy: jetstream::consumer::AckPolicy::Explicit,
+            // Deferred retries stay pending until due, so a low cap would stall new events for
+            // hours after a failure burst; the workers bound the events in flight instead
+            max_ack_pending: MAX_ACK_PENDING,
             ..Default::default()
         })
         .await?;
 
-    let mut messages = consumer.messages().await?;
-
-    while let Some(msg) = messages.next().await {
-        let msg = msg?;
-        let payload: Value = serde_json::from_slice(&msg.payload)?;
-
-        let event_type = payload["event_type"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("missing event_type"))?;
-
-        let lambda_arn_bytes = db.get(event_type.as_bytes())?
-            .ok_or_else(|| anyhow::anyhow!("no rule found for event_type: {}", event_type))?;
-        let lambda_arn = String::from_utf8(lambda_arn_bytes.to_vec())?;
-
-        // let lambda_arn = payload["lambda_arn"]
-        //     .as_str()
-        //     .ok_or_else(|| anyhow::anyhow!("missing lambda_arn"))?;
-        let retry_index = payload
-            .get("retry_index")
-            .and_then(Value::as_u64)
-            .unwrap_or(0);
-
-        // Invoke the Lambda function
-        let invoke_result = lambda_client
-            .invoke()
-            .invocation_type("Event".into())
-            .function_name(lambda_arn)
-            .payload(Blob::new(serde_json::to_vec(&payload)?))
-            .send()
-            .await?;
+    // Fetch no more than the workers can take, so messages wait in the stream rather
+    // than in the client's buffer, where their ack wait would already be running
+    let mut messages = consumer
+        .stream()
+        .max_messages_per_batch(config.concurrency)
+        .messages()
+        .await?;
+    let trigger = Trigger {
+        target_limits: TargetLimits::new(config.target_concurrency),
+        rate_limits: RateLimits::default(),
+        circuits,
+        invocations: Invocations::open(&js, config.invocation_record_ttl).await?,
+        failures: TransientFailures::default(),
+        client,
+        js,
+        lambda_client,
+        rules: RuleCache::new(db.clone()),
+        db,
+        topology,
+        config,
+    };
+    let mut in_flight = FuturesUnordered::new();
+
+    loop {
+        tokio::select! {
+            // Stops pulling on shutdown; a message being handled is always finished and acked first
+            msg = shutdown.interruptible(messages.next()), if in_flight.len() < trigger.config.concurrency => {
+                match msg.flatten() {
+                    Some(Ok(msg)) => in_flight.push(trigger.process(msg)),
+                    Some(Err(e)) => eprintln!("⚠️ Failed to receive an event: {}", e),
+                    None => break,
+                }
+            }
+            Some(result) = in_flight.next() => result?,
+        }
+    }
+    while let Some(result) = in_flight.next().await {
+        result?;
+    }
+
+    if shutdown.is_requested() {
+        println!("⏹️ Lambda trigger stopped");
+    }
+    Ok(())
+}
 
-        let request_id = invoke_result
-            .executed_version()
-            .map(|v| v.to_string())
-            .unwrap_or_else(|| "unknown".to_string()); // Or handle as needed
+/// What the trigger needs to handle one event.
+struct Trigger {
+    client: Client,
+    js: jetstream::Context,
+    lambda_client: aws_sdk_lambda::Client,
+    db: Db,
+    rules: RuleCache,
+    topology: Topology,
+    config: TriggerConfig,
+    target_limits: TargetLimits,
+    rate_limits: RateLimits,
+    circuits: Circuits,
+    invocations: Invocations,
+    failures: TransientFailures,
+}
+
+impl Trigger {
+    /// Handles and settles one message. Fails only when the trigger has to stop.
+    async fn process(&self, msg: jetstream::Message) -> Result<()> {
+        match defer_until_due(&msg).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("⚠️ Failed to defer an event: {}", e);
+                return Ok(());
+            }
+        }
+
+        let result = self.handle_event(&msg).await;
+        settle(&self.js, &self.topology, &self.failures, &msg, result).await
+    }
+
+    async fn handle_event(&self, msg: &jetstream::Message) -> Result<(), MessageError> {
+        let payload = parse_event(msg)?;
+
+        // A retry carries the rule and target it is for; a new event goes to every
+        // target of every rule whose event type or pattern matches
+        let retry_of = payload.get("rule").and_then(Value::as_str).zip(payload.get("target_id").and_then(Value::as_str));
+        let rules = match retry_of {
+            Some((rule_name, _)) => get_rule(&self.db, rule_name).map_err(MessageError::fatal)?.into_iter().collect(),
+            None => self.rules.matching(&payload),
+        };
+        let targets: Vec<(&Rule, &Target)> = rules
+            .iter()
+            .flat_map(|rule| rule.targets.iter().map(move |target| (rule, target)))
+            .filter(|(_, target)| retry_of.is_none_or(|(_, target_id)| target.id == target_id))
+            .collect();
+        if targets.is_empty() {
+            match retry_of {
+                Some((rule_name, _)) => eprintln!("⚠️ Target of retried {} event no longer exists, dropping it", rule_name),
+                None if cloud_event::event_type(&payload).is_none() => {
+                    return Err(MessageError::poison("Event has no event_type or CloudEvents type and matches no rule pattern"));
+                }
+                None => eprintln!("⚠️ No rule matches event {}", payload),
+            }
+        }
+
+        // Held until every target was invoked; a function at its limit sends the message back for later
+        let _permits = self
+            .target_limits
+            .try_acquire(targets.iter().map(|(_, target)| target.lambda_arn.as_str()))
+            .ok_or(MessageError::Busy(TARGET_BUSY_DELAY))?;
+        let rate_limits = || {
+            targets
+                .iter()
+                .filter_map(|(rule, target)| Some((target.lambda_arn.as_str(), rule.rate_limit?)))
+        };
+        loop {
+            match self.rate_limits.try_take(rate_limits()) {
+                Ok(()) => break,
+                Err(wait) if wait <= MAX_RATE_LIMIT_WAIT => tokio::time::sleep(wait).await,
+                Err(wait) => return Err(MessageError::Busy(wait)),
+            }
+        }
+
+        for (index, (rule, target)) in targets.into_iter().enumerate() {
+            // Recorded by handle_target, so a redelivery, e.g. after a crash before the ack,
+            // does not invoke the target again
+            let attempt_key = attempt_key(msg, target);
+            if let Some(attempt_key) = &attempt_key {
+                if let Some(handled_at) = self.invocations.handled_at(attempt_key).await {
+                    println!("⏭️ Target {} of rule {} already handled at {}, not invoking it again", target.id, rule.event_type, handled_at);
+                    continue;
+                }
+            }
 
-        // Prepare status check payload
+            self.handle_target(msg, &payload, rule, target, index == 0, attempt_key.as_deref()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Invokes one target of the event, then publishes its status check, result, retry or
+    /// dead letter. Lambda throttling the first target sends the whole message back.
+    /// The attempt is recorded as handled as soon as the function was invoked, so a failure
+    /// publishing what follows cannot lead to a second invocation.
+    async fn handle_target(
+        &self,
+        msg: &jetstream::Message,
+        payload: &Value,
+        rule: &Rule,
+        target: &Target,
+        first: bool,
+        attempt_key: Option<&str>,
+    ) -> Result<(), MessageError> {
+        let record_attempt = || async {
+            if let Some(attempt_key) = attempt_key {
+                self.invocations.record(attempt_key).await;
+            }
+        };
+        let invoked_at = Utc::now().to_rfc3339();
+        let retry_index = payload.get("retry_index").and_then(Value::as_u64).unwrap_or(0);
+
+        // Prepare status check payload; each target gets its own retry chain, traced by the
+        // event's envelope id, or one derived from the message for an event without an envelope
+        let event_id = payload
+            .get("event_id")
+            .and_then(Value::as_str)
+            .or_else(|| envelope::event_id(payload))
+            .map(str::to_string)
+            .unwrap_or_else(|| message_event_id(msg));
         let mut status_payload = payload.clone();
         status_payload["retry_index"] = retry_index.into();
-        status_payload["event_id"] = msg.info().map(|i| i.stream_sequence.to_string()).unwrap_or_default().into();
-        status_payload["lambda_request_id"] = request_id.into(); // Add RequestId to payload
+        status_payload["event_id"] = event_id.into();
+        status_payload["rule"] = rule.event_type.clone().into();
+        status_payload["target_id"] = target.id.clone().into();
+        status_payload["lambda_arn"] = target.lambda_arn.clone().into();
+        if let Some(result_subject) = rule.resolved_result_subject() {
+            status_payload["result_subject"] = result_subject.into();
+        }
+        // A retry keeps the policy its chain started with
+        if payload.get("retry_policy").is_none() {
+            status_payload["retry_policy"] = serde_json::to_value(&rule.retry_policy)?;
+        }
 
-        // Delay header
-        let delay_secs = get_delay_seconds(retry_index);
-        let mut headers = async_nats::HeaderMap::new();
-        headers.insert("Nats-Delay", format!("{}s", delay_secs));
+        // An open circuit parks the event for this target instead of invoking a function that keeps
+        // failing, unless the event was released from the holding stream as the probe
+        let is_probe = msg.headers.as_ref().is_some_and(|h| h.get(CIRCUIT_PROBE_HEADER).is_some());
+        if !is_probe {
+            if let Admission::Hold(_) = self.circuits.admit(&target.lambda_arn).await {
+                self.js
+                    .publish(self.topology.holding_subject(&target.lambda_arn), serde_json::to_vec(&status_payload)?.into())
+                    .await?
+                    .await?;
+                record_attempt().await;
+                println!("⏸️ Event {} parked, circuit of {} is open", status_payload["event_id"].as_str().unwrap_or(""), target.lambda_arn);
+                return Ok(());
+            }
+        }
 
-        // Publish delayed status event
-        client
-            .publish_with_headers::<String>(
-                "check.lambda.status".into(),
-                headers,
-                serde_json::to_vec(&status_payload)?.into(),
-            )
-            .await?;
+        let input = rule.target_payload(target, payload, status_payload["event_id"].as_str().unwrap_or_default());
+        let invocation = invoke_target(&self.lambda_client, rule.invocation_mode, target, &input, attempt_key).await;
+
+        // A throttle is not a failed attempt: the first target sends the whole message back,
+        // a later one is deferred alone so the targets already invoked are not invoked again
+        if let Err(e) = &invocation {
+            if is_throttle(e) {
+                if first {
+                    return Err(MessageError::Throttled);
+                }
+                let delivered = msg.info().map(|i| i.delivered).unwrap_or(1);
+                let delay = throttle_delay(delivered).as_secs().max(1);
+                defer_throttled(&self.js, &self.topology, &status_payload, delay).await?;
+                record_attempt().await;
+                return Ok(());
+            }
+        }
 
-        match msg.ack().await {
-            Ok(_) => {},
-            Err(e) => return Ok(()),
+        let request_id = invocation.as_ref().map(|i| i.request_id.clone()).unwrap_or_default();
+        status_payload["lambda_request_id"] = request_id.clone().into(); // Add RequestId to payload
+
+        // Attempt history of this target's retry chain, kept for the dead-letter record
+        let mut attempts: Vec<Value> = payload
+            .get("attempts")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        attempts.push(serde_json::to_value(Attempt {
+            retry_index,
+            request_id,
+            invoked_at,
+            failure_reason: None,
+        })?);
+        status_payload["attempts"] = attempts.into();
+
+        // A failed invoke is retried in the target's own retry chain, so the other
+        // targets of the event are not invoked again
+        let invocation = match invocation {
+            Ok(invocation) => {
+                record_attempt().await;
+                invocation
+            }
+            Err(e) => {
+                let reason = format!("Invoke failed: {}", DisplayErrorContext(&e));
+                // Errors returned by Lambda, such as a deleted function or a broken role, count
+                // against the function; network errors are not its fault
+                if matches!(e, SdkError::ServiceError(_)) {
+                    self.circuits.record_failure(&target.lambda_arn, &reason).await;
+                }
+                if is_transient(&e) {
+                    retry_or_dead_letter(&self.js, &self.topology, status_payload, reason).await?;
+                } else {
+                    dead_letter(&self.js, &self.topology, status_payload, reason).await?;
+                }
+                record_attempt().await;
+                return Ok(());
+            }
         };
+
+        // The status check finds an Event invocation by its request id. One without could only be
+        // matched to unrelated log lines, so the attempt counts as failed
+        if rule.invocation_mode == InvocationMode::Event && invocation.request_id.is_empty() {
+            eprintln!("⚠️ Invoke of {} returned no request id, its status cannot be checked", target.lambda_arn);
+            let reason = "Invoke returned no request id, so its status cannot be checked".to_string();
+            retry_or_dead_letter(&self.js, &self.topology, status_payload, reason).await?;
+            return Ok(());
+        }
+
+        if rule.invocation_mode == InvocationMode::RequestResponse {
+            handle_result(&self.client, &self.js, &self.topology, &self.circuits, rule, invocation, status_payload).await?;
+            return Ok(());
+        }
+
+        let headers = delay_headers(self.config.status_check_delay_secs);
+
+        // Publish delayed status event, stored before the message is acked
+        self.js
+            .publish_with_headers(self.topology.status_subject.clone(), headers, serde_json::to_vec(&status_payload)?.into())
+            .await?
+            .await?;
+        Ok(())
+    }
+}
+
+// Lambda rejected the invocation because the function or account is over its concurrency or request rate
+fn is_throttle(error: &SdkError<InvokeError, HttpResponse>) -> bool {
+    error.as_service_error().is_some_and(InvokeError::is_too_many_requests_exception)
+        || error.raw_response().is_some_and(|response| response.status().as_u16() == 429)
+}
+
+// Throttling, Lambda service errors and network failures may pass; other errors,
+// such as a missing function or a rejected payload, will not
+fn is_transient(error: &SdkError<InvokeError, HttpResponse>) -> bool {
+    match error {
+        SdkError::ServiceError(_) => error
+            .raw_response()
+            .is_some_and(|response| response.status().as_u16() == 429 || response.status().is_server_error()),
+        SdkError::ConstructionFailure(_) => false,
+        _ => true,
+    }
+}
+
+/// Invokes the target's Lambda with `input`, the payload the rule prepared for the target.
+/// The request id is what the status checker looks up in the function's
+/// `REPORT RequestId: ...` log line for `Event` invocations.
+///
+/// The idempotency key is the same for every delivery of the attempt, so functions can
+/// drop duplicates. It is added to an object input as `idempotency_key`, or to a CloudEvent
+/// as the `idempotencykey` extension attribute, and, since Lambda
+/// passes the client context to synchronous invocations only, to the client context of
+/// `RequestResponse` invocations as `custom.idempotency_key`.
+async fn invoke_target(
+    lambda_client: &aws_sdk_lambda::Client,
+    mode: InvocationMode,
+    target: &Target,
+    input: &Value,
+    idempotency_key: Option<&str>,
+) -> Result<Invocation, SdkError<InvokeError, HttpResponse>> {
+    let invocation_type = match mode {
+        InvocationMode::Event => InvocationType::Event,
+        InvocationMode::RequestResponse => InvocationType::RequestResponse,
+    };
+    let mut input = input.clone();
+    let mut client_context = None;
+    if let Some(key) = idempotency_key {
+        let field = if cloud_event::is_cloud_event(&input) { IDEMPOTENCY_KEY_ATTRIBUTE } else { IDEMPOTENCY_KEY_FIELD };
+        if let Some(object) = input.as_object_mut() {
+            object.insert(field.to_string(), key.into());
+        }
+        if mode == InvocationMode::RequestResponse {
+            let context = json!({"custom": {IDEMPOTENCY_KEY_FIELD: key}}).to_string();
+            client_context = Some(Base64::encode_string(context.as_bytes()));
+        }
+    }
+
+    let started = Instant::now();
+    let invoke_result = lambda_client
+        .invoke()
+        .invocation_type(invocation_type)
+        .function_name(&target.lambda_arn)
+        .set_client_context(client_context)
+        .payload(Blob::new(input.to_string()))
+        .send()
+        .await?;
+
+    // The x-amzn-RequestId response header, not executed_version(), which is the function version
+    Ok(Invocation {
+        request_id: invoke_result.request_id().unwrap_or_default().to_string(),
+        function_error: invoke_result.function_error().map(str::to_string),
+        response: invoke_result.payload().map(|p| p.as_ref().to_vec()),
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+    })
+}
+
+/// Decides a `RequestResponse` invocation right away: a function error is retried
+/// (or dead-lettered) like a failure found by the status checker, and a successful
+/// response is published to the rule's reply subject, if it has one.
+async fn handle_result(
+    client: &Client,
+    js: &jetstream::Context,
+    topology: &Topology,
+    circuits: &Circuits,
+    rule: &Rule,
+    invocation: Invocation,
+    status_payload: Value,
+) -> Result<()> {
+    let event_id = status_payload["event_id"].as_str().unwrap_or_default().to_string();
+    let lambda_arn = status_payload["lambda_arn"].as_str().unwrap_or_default();
+    let response = invocation.response.unwrap_or_default();
+
+    if let Some(function_error) = invocation.function_error {
+        let failure_reason = format!("{}: {}", function_error, String::from_utf8_lossy(&response));
+        circuits.record_failure(lambda_arn, &failure_reason).await;
+        return retry_or_dead_letter(js, topology, status_payload, failure_reason).await;
+    }
+    circuits.record_success(lambda_arn).await;
+
+    println!("✅ Event {} succeeded (request {})", event_id, invocation.request_id);
+    publish_completion(client, js, topology, &status_payload, Some(invocation.duration_ms), Some(&response)).await?;
+
+    if let Some(reply_subject) = &rule.reply_subject {
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert("Event-Id", event_id.as_str());
+        headers.insert("Lambda-Request-Id", invocation.request_id.as_str());
+        client
+            .publish_with_headers(reply_subject.clone(), headers, response.into())
+            .await?;
     }
 
     Ok(())
 }
 
-fn get_delay_seconds(idx: u64) -> u64 {
-    let delays = [60, 600, 1800, 3600, 14400, 28800, 86400];
-    *delays.get(idx as usize).unwrap_or(&86400)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status_checker::tests::cloudwatch_filter_pattern;
+    use aws_credential_types::Credentials;
+    use aws_sdk_lambda::config::{BehaviorVersion, Region};
+    use aws_smithy_http_client::test_util::capture_request;
+    use aws_smithy_types::body::SdkBody;
+    use serde_json::json;
+
+    const REQUEST_ID: &str = "c6af9ac6-7b61-11e6-9a41-93e8deadbeef";
+
+    fn mocked_lambda(response: http::Response<SdkBody>) -> (aws_sdk_lambda::Client, aws_smithy_http_client::test_util::CaptureRequestReceiver) {
+        let (http_client, request) = capture_request(Some(response));
+        let config = aws_sdk_lambda::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .http_client(http_client)
+            .build();
+        (aws_sdk_lambda::Client::from_conf(config), request)
+    }
+
+    // What Lambda returns for an accepted asynchronous invocation
+    fn accepted_response() -> http::Response<SdkBody> {
+        http::Response::builder()
+            .status(202)
+            .header("x-amzn-RequestId", REQUEST_ID)
+            .header("X-Amz-Executed-Version", "$LATEST")
+            .body(SdkBody::empty())
+            .unwrap()
+    }
+
+    fn target() -> Target {
+        Target {
+            id: "7".to_string(),
+            lambda_arn: "arn:aws:lambda:us-east-1:123456789012:function:orders".to_string(),
+            input: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn invoke_returns_request_id_not_executed_version() {
+        let (lambda_client, _request) = mocked_lambda(accepted_response());
+
+        let invocation = invoke_target(&lambda_client, InvocationMode::Event, &target(), &json!({"event_type": "order.created"}), None)
+            .await
+            .unwrap();
+
+        assert_eq!(invocation.request_id, REQUEST_ID);
+
+        // Never a placeholder the status checker would search CloudWatch Logs for
+        let (lambda_client, _request) = mocked_lambda(http::Response::builder().status(202).body(SdkBody::empty()).unwrap());
+        let invocation = invoke_target(&lambda_client, InvocationMode::Event, &target(), &json!({"event_type": "order.created"}), None)
+            .await
+            .unwrap();
+        assert_eq!(invocation.request_id, "");
+    }
+
+    #[tokio::test]
+    async fn invoke_sends_event_invocation_with_payload() {
+        let (lambda_client, request) = mocked_lambda(accepted_response());
+        let payload = json!({"event_type": "order.created", "id": 1});
+
+        invoke_target(&lambda_client, InvocationMode::Event, &target(), &payload, None).await.unwrap();
+
+        let request = request.expect_request();
+        assert!(request.uri().contains("orders"));
+        assert_eq!(request.headers().get("X-Amz-Invocation-Type"), Some("Event"));
+        let body: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+        assert_eq!(body, payload);
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_is_passed_in_the_input_and_client_context() {
+        let (lambda_client, request) = mocked_lambda(accepted_response());
+        invoke_target(&lambda_client, InvocationMode::RequestResponse, &target(), &json!({"id": 1}), Some("42-1700000000-7"))
+            .await
+            .unwrap();
+
+        let request = request.expect_request();
+        let body: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+        assert_eq!(body, json!({"id": 1, "idempotency_key": "42-1700000000-7"}));
+        let context = request.headers().get("X-Amz-Client-Context").unwrap();
+        let context: Value = serde_json::from_slice(&Base64::decode_vec(context).unwrap()).unwrap();
+        assert_eq!(context, json!({"custom": {"idempotency_key": "42-1700000000-7"}}));
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_is_an_extension_attribute_of_a_cloud_event() {
+        let (lambda_client, request) = mocked_lambda(accepted_response());
+        let input = json!({"specversion": "1.0", "id": "1", "source": "/orders", "type": "order.created", "data": {"id": 1}});
+        invoke_target(&lambda_client, InvocationMode::Event, &target(), &input, Some("42-1700000000-7"))
+            .await
+            .unwrap();
+
+        let body: Value = serde_json::from_slice(request.expect_request().body().bytes().unwrap()).unwrap();
+        assert_eq!(body["idempotencykey"], "42-1700000000-7");
+        assert_eq!(body["data"], json!({"id": 1}));
+    }
+
+    #[tokio::test]
+    async fn status_check_looks_up_the_invocation_request_id() {
+        let (lambda_client, _request) = mocked_lambda(accepted_response());
+        let invocation = invoke_target(&lambda_client, InvocationMode::Event, &target(), &json!({"event_type": "order.created"}), None)
+            .await
+            .unwrap();
+
+        let filter_pattern = cloudwatch_filter_pattern(&target().lambda_arn, &invocation.request_id).await;
+
+        assert_eq!(filter_pattern, format!("\"{}\"", REQUEST_ID));
+    }
+
+    #[tokio::test]
+    async fn throttles_are_transient_and_missing_functions_are_not() {
+        let error_response = |status: u16, error_type: &str| {
+            http::Response::builder()
+                .status(status)
+                .header("x-amzn-RequestId", REQUEST_ID)
+                .header("x-amzn-ErrorType", error_type)
+                .body(SdkBody::from(r#"{"message":"failed"}"#))
+                .unwrap()
+        };
+
+        let (lambda_client, _request) = mocked_lambda(error_response(429, "TooManyRequestsException"));
+        let throttled = invoke_target(&lambda_client, InvocationMode::Event, &target(), &json!({}), None).await.unwrap_err();
+        assert!(is_transient(&throttled));
+        assert!(is_throttle(&throttled));
+
+        let (lambda_client, _request) = mocked_lambda(error_response(404, "ResourceNotFoundException"));
+        let missing = invoke_target(&lambda_client, InvocationMode::Event, &target(), &json!({}), None).await.unwrap_err();
+        assert!(!is_transient(&missing));
+        assert!(!is_throttle(&missing));
+    }
+
+    #[tokio::test]
+    async fn request_response_reads_function_error_and_payload() {
+        let (lambda_client, request) = mocked_lambda(
+            http::Response::builder()
+                .status(200)
+                .header("x-amzn-RequestId", REQUEST_ID)
+                .header("X-Amz-Function-Error", "Unhandled")
+                .body(SdkBody::from(r#"{"errorMessage":"boom"}"#))
+                .unwrap(),
+        );
+
+        let invocation = invoke_target(&lambda_client, InvocationMode::RequestResponse, &target(), &json!({}), None)
+            .await
+            .unwrap();
+
+        let request = request.expect_request();
+        assert_eq!(request.headers().get("X-Amz-Invocation-Type"), Some("RequestResponse"));
+        assert_eq!(invocation.request_id, REQUEST_ID);
+        assert_eq!(invocation.function_error.as_deref(), Some("Unhandled"));
+        assert_eq!(invocation.response.as_deref(), Some(br#"{"errorMessage":"boom"}"#.as_slice()));
+    }
 }
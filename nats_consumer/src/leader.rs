@@ -0,0 +1,105 @@
+// leader.rs
+use async_nats::jetstream::{self, kv};
+use async_nats::Client;
+use std::env;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::sleep;
+
+use crate::shutdown::Shutdown;
+
+const LEADER_BUCKET: &str = "scheduler_leader";
+const LEADER_KEY: &str = "leader";
+
+#[derive(Clone)]
+pub struct LeaderConfig {
+    /// How long a lease is valid without renewal; a new leader takes over within this period
+    pub lease: Duration,
+    /// Identifies this replica in the lease value
+    pub instance_id: String,
+}
+
+impl LeaderConfig {
+    pub fn from_env() -> Self {
+        let lease_secs = env::var("SCHEDULER_LEASE_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(15);
+        let instance_id = env::var("INSTANCE_ID")
+            .unwrap_or_else(|_| format!("{:016x}", rand::random::<u64>()));
+
+        LeaderConfig {
+            lease: Duration::from_secs(lease_secs),
+            instance_id,
+        }
+    }
+}
+
+/// Campaigns for the scheduler lease in a JetStream KV bucket and publishes
+/// leadership changes on `is_leader`.
+///
+/// The bucket's `max_age` is the lease TTL: the leader renews the key every third
+/// of the lease, and if it stops doing so the key expires and another replica's
+/// `create` succeeds.
+pub async fn run_leader_election(
+    client: Client,
+    config: LeaderConfig,
+    is_leader: watch::Sender<bool>,
+    mut shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let js = jetstream::new(client);
+    let store = js
+        .create_or_update_key_value(kv::Config {
+            bucket: LEADER_BUCKET.to_string(),
+            history: 1,
+            max_age: config.lease,
+            ..Default::default()
+        })
+        .await?;
+
+    println!("✅ Leader election started as {}", config.instance_id);
+
+    let renew_every = config.lease / 3;
+    let mut revision: Option<u64> = None;
+
+    loop {
+        let value = config.instance_id.clone().into();
+        revision = match revision {
+            // Leader: renew the lease, stepping down if anyone else has written the key
+            Some(rev) => match store.update(LEADER_KEY, value, rev).await {
+                Ok(rev) => Some(rev),
+                Err(e) => {
+                    eprintln!("⚠️ Lost scheduler leadership: {}", e);
+                    None
+                }
+            },
+            // Follower: only succeeds once the key is absent, i.e. the old lease expired
+            None => match store.create(LEADER_KEY, value).await {
+                Ok(rev) => {
+                    println!("👑 Acquired scheduler leadership");
+                    Some(rev)
+                }
+                Err(_) => None,
+            },
+        };
+
+        is_leader.send_if_modified(|leader| {
+            let changed = *leader != revision.is_some();
+            *leader = revision.is_some();
+            changed
+        });
+
+        if shutdown.interruptible(sleep(renew_every)).await.is_none() {
+            break;
+        }
+    }
+
+    // Release the lease so another replica takes over without waiting for it to expire
+    is_leader.send_replace(false);
+    if let Some(rev) = revision {
+        store.delete_expect_revision(LEADER_KEY, Some(rev)).await?;
+        println!("⏹️ Released scheduler leadership");
+    }
+    Ok(())
+}
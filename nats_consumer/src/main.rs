@@ -1,33 +1,62 @@
 // main.rs
 
-use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_cloudwatchlogs::Client as CloudWatchLogsClient;
-use aws_sdk_lambda::Client as LambdaClient;
-use lambda_trigger::run_lambda_trigger;
+use circuit::{run_held_event_releaser, CircuitConfig};
+use cron::run_scheduler;
+use lambda_trigger::{run_lambda_trigger, TriggerConfig};
+use leader::{run_leader_election, LeaderConfig};
+use nats_common::topology::Topology;
 use sled::Db;
 use status_checker::run_status_checker;
 use utils::get_sqlite_path;
 use std::env;
 use std::error::Error;
-use tokio::try_join;
+use tokio::sync::watch;
+use shutdown::Shutdown;
+use supervisor::supervise;
+use std::time::Duration;
+use tokio::join;
 
 
+mod circuit;
+mod completion;
+mod concurrency;
+mod cron;
+mod delay;
+mod failure;
+mod idempotency;
+#[cfg(test)]
+mod integration_tests;
 mod lambda_trigger;
+mod leader;
+mod rate_limit;
+mod retry;
+mod shutdown;
 mod utils;
 mod status_checker;
+mod supervisor;
 use aws_credential_types::Credentials;
 use aws_types::region::Region;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error >> {
+    let shutdown = Shutdown::listen();
+
     // Load environment variables
+    let topology = Topology::load()?;
+    println!("✅ Topology: events on {} ({}), status checks on {} ({})",
+        topology.event_subject, topology.event_stream, topology.status_subject, topology.status_stream);
     let nats_url = env::var("NATS_URL").unwrap_or_else(|_| "nats://localhost:4222".to_string());
     let nats_client = async_nats::connect(&nats_url).await?;
     let nats_client2 = nats_client.clone(); // Clone for the second task
 
     // Connect to NATS
     println!("✅ Connected to NATS at {}", nats_url);
-    let db_path = get_sqlite_path().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?; // Shared DB path with lambda_trigger service
+    let db_path = get_sqlite_path().map_err(|e| std::io::Error::other(e.to_string()))?; // Shared DB path with lambda_trigger service
     let db: Db = sled::open(db_path)?;
+    let migrated = nats_common::rule::migrate_legacy_rules(&db)?;
+    if migrated > 0 {
+        println!("✅ Migrated {} legacy rules", migrated);
+    }
 
     
     // Load AWS credentials from DB
@@ -56,10 +85,56 @@ async fn main() -> Result<(), Box<dyn Error >> {
 
     let logs_client = CloudWatchLogsClient::new(&config);
     println!("✅ Initialized AWS Lambda client");
-    // Run the lambda trigger loop
-    try_join!(
-        run_lambda_trigger(nats_client.clone(), lambda_client.clone(),db.clone()),
-        run_status_checker(nats_client2, lambda_client,logs_client)
-    )?;
+
+    // Trigger and status checker run on every replica; only the elected leader fires schedules
+    let (leader_tx, leader_rx) = watch::channel(false);
+
+    let trigger_config = TriggerConfig::from_env();
+    let leader_config = LeaderConfig::from_env();
+    let circuit_config = CircuitConfig::from_env();
+    // Below the 30s Kubernetes grace period by default, so the flushes below still run
+    let drain_timeout = Duration::from_secs(
+        env::var("SHUTDOWN_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(25),
+    );
+
+    // Each task is restarted with backoff when it fails, so one crashed task does not stop the others
+    let services = async {
+        join!(
+            supervise("Lambda trigger", shutdown.clone(), || run_lambda_trigger(
+                nats_client.clone(), lambda_client.clone(), db.clone(), topology.clone(), trigger_config.clone(),
+                circuit_config.clone(), shutdown.clone()
+            )),
+            supervise("Status checker", shutdown.clone(), || run_status_checker(
+                nats_client2.clone(), lambda_client.clone(), logs_client.clone(), topology.clone(), circuit_config.clone(),
+                shutdown.clone()
+            )),
+            supervise("Held event releaser", shutdown.clone(), || run_held_event_releaser(
+                nats_client.clone(), topology.clone(), circuit_config.clone(), shutdown.clone()
+            )),
+            supervise("Leader election", shutdown.clone(), || {
+                // The lease is not renewed while the election restarts, so stop scheduling until it is won again
+                leader_tx.send_replace(false);
+                run_leader_election(nats_client.clone(), leader_config.clone(), leader_tx.clone(), shutdown.clone())
+            }),
+            supervise("Scheduler", shutdown.clone(), || run_scheduler(
+                nats_client.clone(), db.clone(), topology.clone(), leader_rx.clone(), shutdown.clone()
+            ))
+        )
+    };
+    let mut drain_started = shutdown.clone();
+    let drain_deadline = async {
+        drain_started.requested().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    tokio::select! {
+        _ = services => println!("✅ In-flight work finished"),
+        _ = drain_deadline => eprintln!("⚠️ In-flight work did not finish within {:?}, stopping anyway", drain_timeout),
+    }
+
+    // Publishes and acks are buffered by the client, sled writes by its page cache
+    nats_client.flush().await?;
+    db.flush_async().await?;
+    println!("👋 Shut down");
     Ok(())
 }
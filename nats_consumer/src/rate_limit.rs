@@ -0,0 +1,91 @@
+// rate_limit.rs
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token buckets that cap how often each Lambda function is invoked, so the
+/// databases and APIs it calls are not flooded when a backlog is replayed.
+#[derive(Default)]
+pub struct RateLimits {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // Up to one second of invocations, and at least one, may run in a burst
+    fn refill(&mut self, per_second: f64, now: Instant) {
+        let capacity = per_second.max(1.0);
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.updated = now;
+    }
+
+    fn wait(&self, per_second: f64) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / per_second).max(0.0))
+    }
+}
+
+impl RateLimits {
+    /// Takes one token per function in `limits`, given as (Lambda ARN, invocations per
+    /// second). Takes none and returns the time until all are available if any bucket is empty.
+    pub fn try_take<'a>(&self, limits: impl IntoIterator<Item = (&'a str, f64)>) -> Result<(), Duration> {
+        self.try_take_at(limits, Instant::now())
+    }
+
+    fn try_take_at<'a>(&self, limits: impl IntoIterator<Item = (&'a str, f64)>, now: Instant) -> Result<(), Duration> {
+        let limits: Vec<(&str, f64)> = limits.into_iter().filter(|(_, per_second)| *per_second > 0.0).collect();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut wait = Duration::ZERO;
+        for (arn, per_second) in &limits {
+            let bucket = buckets.entry(arn.to_string()).or_insert_with(|| Bucket {
+                tokens: per_second.max(1.0),
+                updated: now,
+            });
+            bucket.refill(*per_second, now);
+            wait = wait.max(bucket.wait(*per_second));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (arn, _) in &limits {
+            if let Some(bucket) = buckets.get_mut(*arn) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_the_configured_rate() {
+        let limits = RateLimits::default();
+        let start = Instant::now();
+        for _ in 0..2 {
+            assert!(limits.try_take_at([("orders", 2.0)], start).is_ok());
+        }
+        assert_eq!(limits.try_take_at([("orders", 2.0)], start), Err(Duration::from_millis(500)));
+        // Other functions have their own bucket
+        assert!(limits.try_take_at([("billing", 2.0)], start).is_ok());
+
+        assert!(limits.try_take_at([("orders", 2.0)], start + Duration::from_millis(500)).is_ok());
+        assert!(limits.try_take_at([("orders", 2.0)], start + Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn takes_nothing_unless_every_function_has_a_token() {
+        let limits = RateLimits::default();
+        let start = Instant::now();
+        assert!(limits.try_take_at([("slow", 0.5)], start).is_ok());
+
+        assert_eq!(limits.try_take_at([("fast", 1.0), ("slow", 0.5)], start), Err(Duration::from_secs(2)));
+        assert!(limits.try_take_at([("fast", 1.0)], start).is_ok());
+    }
+}
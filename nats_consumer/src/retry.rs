@@ -0,0 +1,111 @@
+// retry.rs
+use async_nats::jetstream;
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use nats_common::dead_letter::DeadLetter;
+use nats_common::envelope::{retry_msg_id, MSG_ID_HEADER};
+use nats_common::retry_policy::RetryPolicy;
+use nats_common::topology::Topology;
+use serde_json::Value;
+
+use crate::delay::delay_headers;
+
+/// Handles a failed invocation of one target. `tracked` is the status payload of the
+/// attempt (the event plus `retry_index`, `event_id`, `target_id`, `attempts`,
+/// `retry_policy`, ...). The event is republished to the event subject for that target after
+/// the policy's backoff, or moved to the dead-letter stream once the policy gives up.
+/// Returns once the stream stored it, so the caller can ack the message it handled.
+pub async fn retry_or_dead_letter(
+    js: &jetstream::Context,
+    topology: &Topology,
+    mut tracked: Value,
+    failure_reason: String,
+) -> Result<()> {
+    let retry_index = tracked["retry_index"].as_u64().unwrap_or(0);
+    let event_id = tracked["event_id"].as_str().unwrap_or("").to_string();
+    let target_id = tracked["target_id"].as_str().unwrap_or("").to_string();
+
+    record_failure(&mut tracked, &failure_reason);
+
+    // The policy of the rule when the event was first invoked
+    let policy: RetryPolicy = tracked
+        .get("retry_policy")
+        .and_then(|p| serde_json::from_value(p.clone()).ok())
+        .unwrap_or_default();
+    let delay = policy.delay_secs(retry_index + 1, rand::random());
+
+    let give_up = if retry_index >= policy.max_attempts as u64 {
+        Some("retries exhausted")
+    } else if exceeds_max_age(&tracked, &policy, delay) {
+        Some("max event age exceeded")
+    } else {
+        None
+    };
+    if let Some(give_up) = give_up {
+        return dead_letter(js, topology, tracked, format!("{} ({})", failure_reason, give_up)).await;
+    }
+
+    tracked["retry_index"] = (retry_index + 1).into();
+
+    let mut headers = delay_headers(delay);
+    headers.insert(MSG_ID_HEADER, retry_msg_id(&event_id, &target_id, retry_index + 1).as_str());
+
+    js.publish_with_headers(topology.event_subject.clone(), headers, serde_json::to_vec(&tracked)?.into())
+        .await?
+        .await?;
+    println!("⚠️ Event {} failed ({}), retry {} in {}s", event_id, failure_reason, retry_index + 1, delay);
+
+    Ok(())
+}
+
+/// Republishes the event for the one target Lambda throttled, after `delay_secs`. The
+/// throttled invocation is not an attempt, so `tracked` keeps its retry index and attempts.
+pub async fn defer_throttled(js: &jetstream::Context, topology: &Topology, tracked: &Value, delay_secs: u64) -> Result<()> {
+    js.publish_with_headers(topology.event_subject.clone(), delay_headers(delay_secs), serde_json::to_vec(tracked)?.into())
+        .await?
+        .await?;
+    println!("⏸️ Event {} throttled, invoking its target again in {}s", tracked["event_id"].as_str().unwrap_or(""), delay_secs);
+    Ok(())
+}
+
+/// Moves a failed attempt to the dead-letter stream without retrying it, e.g. when
+/// the failure cannot pass or the retry policy gave up.
+pub async fn dead_letter(
+    js: &jetstream::Context,
+    topology: &Topology,
+    mut tracked: Value,
+    failure_reason: String,
+) -> Result<()> {
+    record_failure(&mut tracked, &failure_reason);
+    let dead_letter = DeadLetter::from_status_payload(&tracked, failure_reason, Utc::now().to_rfc3339());
+    js.publish(topology.dead_letter_subject.clone(), serde_json::to_vec(&dead_letter)?.into())
+        .await?
+        .await?;
+    eprintln!("❌ Event {} failed ({}), moved to the dead-letter stream", dead_letter.event_id, dead_letter.failure_reason);
+    Ok(())
+}
+
+// Records why the latest attempt failed, unless it already is
+fn record_failure(tracked: &mut Value, failure_reason: &str) {
+    if let Some(last_attempt) = tracked["attempts"].as_array_mut().and_then(|a| a.last_mut()) {
+        if last_attempt.get("failure_reason").is_none_or(Value::is_null) {
+            last_attempt["failure_reason"] = failure_reason.into();
+        }
+    }
+}
+
+// Whether the retry would run after the event reached the policy's max age,
+// counted from the first invocation of this target
+fn exceeds_max_age(tracked: &Value, policy: &RetryPolicy, delay_secs: u64) -> bool {
+    let Some(max_age) = policy.max_event_age_secs else {
+        return false;
+    };
+    let first_invoked_at = tracked["attempts"]
+        .get(0)
+        .and_then(|a| a["invoked_at"].as_str())
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok());
+    match first_invoked_at {
+        Some(first) => Utc::now() + TimeDelta::seconds(delay_secs as i64) - first.with_timezone(&Utc) > TimeDelta::seconds(max_age as i64),
+        None => false,
+    }
+}
@@ -0,0 +1,89 @@
+// shutdown.rs
+use std::future::Future;
+use tokio::sync::watch;
+
+/// Signals every service to stop once SIGTERM or SIGINT is received.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// A shutdown that is requested by sending `true` on the returned sender.
+    pub fn new() -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(false);
+        (tx, Shutdown(rx))
+    }
+
+    /// Starts listening for SIGTERM and SIGINT.
+    pub fn listen() -> Self {
+        let (tx, shutdown) = Shutdown::new();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            println!("⏹️ Shutdown requested, finishing in-flight work");
+            tx.send_replace(true);
+        });
+        shutdown
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown has been requested.
+    pub async fn requested(&mut self) {
+        // A sender dropped without requesting shutdown means it never will be
+        if self.0.wait_for(|requested| *requested).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Runs `future` unless shutdown is requested first, in which case it is dropped
+    /// and `None` is returned. Only wrap waits that are safe to abandon, such as
+    /// pulling the next message, never the handling of one.
+    pub async fn interruptible<F: Future>(&mut self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            _ = self.requested() => None,
+            output = future => Some(output),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn interrupts_waits_once_requested() {
+        let (tx, mut shutdown) = Shutdown::new();
+        assert_eq!(shutdown.interruptible(async { 1 }).await, Some(1));
+
+        tx.send_replace(true);
+        assert!(shutdown.is_requested());
+        assert_eq!(shutdown.interruptible(std::future::pending::<()>()).await, None);
+    }
+
+    #[tokio::test]
+    async fn dropped_sender_never_requests_shutdown() {
+        let (tx, mut shutdown) = Shutdown::new();
+        drop(tx);
+        let waited = tokio::time::timeout(Duration::from_millis(50), shutdown.requested()).await;
+        assert!(waited.is_err());
+    }
+}
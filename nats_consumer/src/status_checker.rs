@@ -1,89 +1,154 @@
-use async_nats::{Client, jetstream,HeaderMap};
+use async_nats::{Client, jetstream};
 use aws_sdk_lambda::Client as LambdaClient;
 use futures::StreamExt;
 use serde_json::Value;
 use anyhow::Result;
+use aws_sdk_cloudwatchlogs::Client as CloudWatchLogsClient;
+use chrono::{DateTime, TimeDelta, Utc};
+use nats_common::topology::Topology;
 use std::time::Duration;
-use tokio::time::sleep;
-use aws_sdk_cloudwatchlogs::{types::FilteredLogEvent, Client as CloudWatchLogsClient};
 
-pub async fn run_status_checker(client: Client,  lambda_client: LambdaClient,logs_client: CloudWatchLogsClient) -> Result<(), async_nats::Error> {
-    // Connect to NATS
-    // let client = async_nats::connect(nats_url).await?;
+use crate::circuit::{CircuitConfig, Circuits};
+use crate::completion::publish_completion;
+use crate::delay::{defer_until_due, MAX_ACK_PENDING};
+use crate::failure::{settle, MessageError, TransientFailures};
+use crate::retry::retry_or_dead_letter;
+use crate::shutdown::Shutdown;
+use crate::utils::ensure_stream;
+
+// How soon an invocation without a REPORT line yet is checked again
+const REPORT_RECHECK_DELAY: Duration = Duration::from_secs(30);
+// An invocation still unreported this long after it was invoked counts as failed. Covers the
+// 15 minute Lambda timeout, time in Lambda's async queue and log ingestion
+const MAX_REPORT_WAIT_SECS: i64 = 30 * 60;
+// Log lines are searched from this long before the invocation, for clock skew between hosts
+const LOG_SEARCH_SLACK: TimeDelta = TimeDelta::minutes(5);
+// Pages of log events read per check; an invocation not found within them is checked again later
+const MAX_LOG_PAGES: usize = 10;
+
+/// What CloudWatch Logs says about one invocation.
+#[derive(Debug, PartialEq)]
+struct LambdaStatus {
+    // Whether the REPORT line that ends the invocation's logs was found
+    reported: bool,
+    failure_reason: Option<String>,
+    // From the REPORT line
+    duration_ms: Option<f64>,
+}
+
+pub async fn run_status_checker(
+    client: Client,
+    _lambda_client: LambdaClient,
+    logs_client: CloudWatchLogsClient,
+    topology: Topology,
+    circuit_config: CircuitConfig,
+    mut shutdown: Shutdown,
+) -> Result<(), async_nats::Error> {
     let js = jetstream::new(client.clone());
+    let circuits = Circuits::open(&js, &topology, circuit_config).await?;
 
-    // Create or bind to the stream & consumer
-    // let stream = js.get_stream("MY_EVENTS_STREAM").await?;
-    let stream = match js.get_stream("status_bridge").await {
-        Ok(s) => {
-            println!("✅ Stream found");
-            s
-        }
-        Err(e) => {
-           // Make sure your function returns `Box<dyn Error>`
-            match js.create_stream(jetstream::stream::Config {
-                name: "status_bridge".to_string(),
-                subjects: vec!["my.status".to_string()],
-                ..Default::default()
-            }).await {
-                Ok(s) => {
-                    println!("✅ Successfully created stream");
-                    s
-                }
-                Err(e) => {
-                    println!("❌ Failed to create stream: {:?}", e);
-                    return Err(Box::new(e)); // Make sure your function returns `Box<dyn Error>`
-                }
-            }
-        }
-    };
+    let stream = ensure_stream(&js, jetstream::stream::Config {
+        name: topology.status_stream.clone(),
+        subjects: vec![topology.status_subject.clone()],
+        ..Default::default()
+    })
+    .await?;
+
+    // Events that exhaust their retries are kept here until they are redriven or purged from the web UI
+    ensure_stream(&js, jetstream::stream::Config {
+        name: topology.dead_letter_stream.clone(),
+        subjects: vec![topology.dead_letter_subject.clone()],
+        ..Default::default()
+    })
+    .await?;
 
     let consumer = stream
         .create_consumer(jetstream::consumer::pull::Config {
-            durable_name: Some("lambda_trigger".into()),
+            durable_name: Some(topology.status_durable.clone()),
             ack_policy: jetstream::consumer::AckPolicy::Explicit,
+            max_ack_pending: MAX_ACK_PENDING,
             ..Default::default()
         })
         .await?;
 
     let mut messages = consumer.messages().await?;
+    let failures = TransientFailures::default();
 
-    while let Some(msg) = messages.next().await {
-        let msg = msg?;
-        let payload: Value = serde_json::from_slice(&msg.payload)?;
-
-        let retry_index = payload["retry_index"].as_u64().unwrap_or(0);
-        let event_id = payload["event_id"].as_str().unwrap_or("");
-        let lambda_arn = payload["lambda_arn"].as_str().unwrap();
-        let lambda_request_id = payload["lambda_request_id"].as_str().unwrap_or("");
-
-        let lambda_failed = check_lambda_status(&logs_client, lambda_arn, lambda_request_id).await?;
-
+    // Stops pulling on shutdown; a message being handled is always finished and acked first
+    while let Some(msg) = shutdown.interruptible(messages.next()).await.flatten() {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("⚠️ Failed to receive a status check: {}", e);
+                continue;
+            }
+        };
+        match defer_until_due(&msg).await {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("⚠️ Failed to defer a status check: {}", e);
+                continue;
+            }
+        }
 
-        if lambda_failed && retry_index < 6 {
-            let mut retry_payload = payload.clone();
-            retry_payload["retry_index"] = (retry_index + 1).into();
+        let result = handle_status_check(&client, &js, &logs_client, &topology, &circuits, &msg).await;
+        settle(&js, &topology, &failures, &msg, result).await?;
+    }
 
-            let delay = get_delay_seconds(retry_index + 1);
+    if shutdown.is_requested() {
+        println!("⏹️ Status checker stopped");
+    }
+    Ok(())
+}
 
-            let mut headers = async_nats::HeaderMap::new();
+async fn handle_status_check(
+    client: &Client,
+    js: &jetstream::Context,
+    logs_client: &CloudWatchLogsClient,
+    topology: &Topology,
+    circuits: &Circuits,
+    msg: &jetstream::Message,
+) -> Result<(), MessageError> {
+    let payload: Value = serde_json::from_slice(&msg.payload)
+        .map_err(|e| MessageError::poison(format!("Status check is not valid JSON: {}", e)))?;
+    let lambda_arn = payload["lambda_arn"]
+        .as_str()
+        .ok_or_else(|| MessageError::poison("Status check has no lambda_arn"))?;
+    let lambda_request_id = payload["lambda_request_id"].as_str().unwrap_or("");
 
+    // When the attempt was invoked, or else when its status check was published
+    let invoked_at = payload["attempts"]
+        .as_array()
+        .and_then(|attempts| attempts.last())
+        .and_then(|attempt| attempt["invoked_at"].as_str())
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        .map(|at| at.with_timezone(&Utc))
+        .or_else(|| DateTime::from_timestamp(msg.info().ok()?.published.unix_timestamp(), 0))
+        .unwrap_or_else(Utc::now);
 
-            headers.insert("Nats-Delay", format!("{}s", delay));
-            headers.insert("Nats-Msg-Id", event_id);
+    let mut status = check_lambda_status(logs_client, lambda_arn, lambda_request_id, invoked_at).await?;
 
-            client
-                .publish_with_headers::<String>(
-                    "my.event".into(),
-                    headers,
-                    serde_json::to_vec(&retry_payload)?.into(),
-                )
-                .await?;
+    // The function may still run, or its logs may not be ingested yet
+    if !status.reported {
+        let invoked_secs_ago = (Utc::now() - invoked_at).num_seconds();
+        if invoked_secs_ago < MAX_REPORT_WAIT_SECS {
+            println!("⏰ Request {} of {} not reported yet, checking again in {:?}", lambda_request_id, lambda_arn, REPORT_RECHECK_DELAY);
+            return Err(MessageError::Busy(REPORT_RECHECK_DELAY));
         }
-
-        msg.ack().await?;
+        status.failure_reason = Some(format!("No REPORT line in CloudWatch Logs {}s after the invocation", invoked_secs_ago));
     }
 
+    match status.failure_reason {
+        Some(failure_reason) => {
+            circuits.record_failure(lambda_arn, &failure_reason).await;
+            retry_or_dead_letter(js, topology, payload, failure_reason).await?
+        }
+        None => {
+            circuits.record_success(lambda_arn).await;
+            publish_completion(client, js, topology, &payload, status.duration_ms, None).await?
+        }
+    }
     Ok(())
 }
 
@@ -92,36 +157,131 @@ async fn check_lambda_status(
     logs_client: &CloudWatchLogsClient,
     lambda_arn: &str,
     request_id: &str,
-) -> Result<bool, anyhow::Error> {
+    invoked_at: DateTime<Utc>,
+) -> Result<LambdaStatus, anyhow::Error> {
     // Extract Lambda function name from ARN
     let function_name = lambda_arn
         .split(':')
-        .last()
+        .next_back()
         .ok_or_else(|| anyhow::anyhow!("Invalid Lambda ARN"))?;
 
-    // Query CloudWatch Logs for the Lambda execution
+    // Every line of the invocation carries its request id: START, the function's own
+    // output, errors, END and REPORT. Only logs from around the invocation on are searched
     let log_group_name = format!("/aws/lambda/{}", function_name);
-    let filter_output = logs_client
-        .filter_log_events()
-        .log_group_name(&log_group_name)
-        .filter_pattern(&format!("REPORT RequestId: {}", request_id))
-        .send()
-        .await?;
+    let start_time = (invoked_at - LOG_SEARCH_SLACK).timestamp_millis();
+    let mut lines = Vec::new();
+    let mut next_token = None;
+    for _ in 0..MAX_LOG_PAGES {
+        let filter_output = logs_client
+            .filter_log_events()
+            .log_group_name(&log_group_name)
+            .filter_pattern(format!("\"{}\"", request_id))
+            .start_time(start_time)
+            .set_next_token(next_token)
+            .send()
+            .await?;
+        lines.extend(filter_output.events.unwrap_or_default().into_iter().filter_map(|event| event.message));
+        next_token = filter_output.next_token;
+        if next_token.is_none() {
+            break;
+        }
+    }
 
-    // Check if the log indicates a failure (e.g., by parsing the log events)
-    let lambda_failed = log_events_indicate_failure(&filter_output.events.unwrap_or_default());
-    Ok(lambda_failed)
+    Ok(parse_status(&lines))
 }
 
-fn log_events_indicate_failure(events: &[FilteredLogEvent]) -> bool {
-    // Implement logic to parse log events and determine if the Lambda failed
-    // For example, look for "Task timed out" or "ERROR" in the logs
-    events.iter().any(|event| {
-        event.message().map(|msg| msg.contains("ERROR") || msg.contains("Task timed out")).unwrap_or(false)
-    })
+// Reads the status from the invocation's log lines. The REPORT line looks like
+// "REPORT RequestId: ...\tDuration: 102.25 ms\tBilled Duration: 103 ms\t...\tStatus: timeout"
+fn parse_status(lines: &[String]) -> LambdaStatus {
+    let report = lines.iter().find(|line| line.starts_with("REPORT RequestId:"));
+    let report_field = |name: &str| {
+        report?.split('\t').find_map(|field| field.trim().strip_prefix(name)).map(str::trim)
+    };
+
+    // The REPORT status covers timeouts and crashes; errors the runtime logs are found in the other lines
+    let report_status = report_field("Status:").filter(|status| *status != "success");
+    let failure_reason = match report_status {
+        Some(status) => Some(report.map(|line| line.trim().to_string()).unwrap_or_else(|| status.to_string())),
+        None => lines
+            .iter()
+            .filter(|line| !line.starts_with("REPORT RequestId:"))
+            .find(|line| line.contains("ERROR") || line.contains("Task timed out"))
+            .map(|line| line.trim().to_string()),
+    };
+
+    LambdaStatus {
+        reported: report.is_some(),
+        failure_reason,
+        duration_ms: report_field("Duration:").and_then(|d| d.strip_suffix(" ms")?.parse().ok()),
+    }
 }
 
-fn get_delay_seconds(index: u64) -> u64 {
-    let delays = [60, 600, 1800, 3600, 14400, 28800, 86400];
-    *delays.get(index as usize).unwrap_or(&86400)
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use aws_credential_types::Credentials;
+    use aws_sdk_cloudwatchlogs::config::{BehaviorVersion, Region};
+    use aws_smithy_http_client::test_util::capture_request;
+    use aws_smithy_types::body::SdkBody;
+
+    /// Runs `check_lambda_status` against a mocked CloudWatch Logs client and returns
+    /// the filter pattern it queried with, checking the search starts before the invocation.
+    pub(crate) async fn cloudwatch_filter_pattern(lambda_arn: &str, request_id: &str) -> String {
+        let response = http::Response::builder()
+            .status(200)
+            .body(SdkBody::from(r#"{"events": []}"#))
+            .unwrap();
+        let (http_client, request) = capture_request(Some(response));
+        let config = aws_sdk_cloudwatchlogs::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .http_client(http_client)
+            .build();
+        let logs_client = CloudWatchLogsClient::from_conf(config);
+
+        let invoked_at = Utc::now();
+        let status = check_lambda_status(&logs_client, lambda_arn, request_id, invoked_at).await.unwrap();
+        assert!(!status.reported);
+
+        let request = request.expect_request();
+        let body: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["logGroupName"], "/aws/lambda/orders");
+        assert_eq!(body["startTime"], (invoked_at - LOG_SEARCH_SLACK).timestamp_millis());
+        body["filterPattern"].as_str().unwrap().to_string()
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn errors_logged_before_the_report_line_fail_the_invocation() {
+        let status = parse_status(&lines(&[
+            "START RequestId: r1 Version: $LATEST",
+            "2025-01-01T00:00:00.000Z\tr1\tERROR\tInvoke Error \t{\"errorMessage\":\"boom\"}",
+            "END RequestId: r1",
+            "REPORT RequestId: r1\tDuration: 102.25 ms\tBilled Duration: 103 ms\tMemory Size: 128 MB\tMax Memory Used: 70 MB",
+        ]));
+        assert!(status.reported);
+        assert!(status.failure_reason.unwrap().contains("boom"));
+        assert_eq!(status.duration_ms, Some(102.25));
+    }
+
+    #[test]
+    fn report_status_marks_timeouts_and_a_missing_report_is_not_a_success() {
+        let status = parse_status(&lines(&[
+            "START RequestId: r2 Version: $LATEST",
+            "END RequestId: r2",
+            "REPORT RequestId: r2\tDuration: 3000.00 ms\tBilled Duration: 3000 ms\tStatus: timeout",
+        ]));
+        assert!(status.failure_reason.unwrap().contains("Status: timeout"));
+
+        let succeeded = parse_status(&lines(&["START RequestId: r3", "END RequestId: r3", "REPORT RequestId: r3\tDuration: 5.00 ms"]));
+        assert_eq!(succeeded, LambdaStatus { reported: true, failure_reason: None, duration_ms: Some(5.0) });
+
+        let running = parse_status(&lines(&["START RequestId: r4 Version: $LATEST"]));
+        assert!(!running.reported);
+        assert_eq!(running.failure_reason, None);
+    }
 }
@@ -0,0 +1,49 @@
+// supervisor.rs
+use futures::FutureExt;
+use std::fmt::Display;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+use crate::shutdown::Shutdown;
+
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+// A task that ran this long was healthy, so its next crash is restarted quickly again
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+/// Runs the task returned by `start` until shutdown, restarting it with exponential
+/// backoff whenever it returns, fails or panics.
+pub async fn supervise<F, Fut, E>(name: &str, mut shutdown: Shutdown, mut start: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    let mut delay = MIN_RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        let outcome = AssertUnwindSafe(start()).catch_unwind().await;
+        if shutdown.is_requested() {
+            if let Ok(Err(e)) = outcome {
+                eprintln!("❌ {} failed while shutting down: {}", name, e);
+            }
+            return;
+        }
+
+        if started.elapsed() >= HEALTHY_AFTER {
+            delay = MIN_RESTART_DELAY;
+        }
+        match outcome {
+            Ok(Ok(())) => eprintln!("⚠️ {} stopped, restarting in {:?}", name, delay),
+            Ok(Err(e)) => eprintln!("❌ {} failed: {}, restarting in {:?}", name, e, delay),
+            Err(_) => eprintln!("❌ {} panicked, restarting in {:?}", name, delay),
+        }
+
+        if shutdown.interruptible(sleep(delay)).await.is_none() {
+            return;
+        }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
@@ -1,6 +1,6 @@
+use async_nats::jetstream::{self, stream::Stream};
 use std::fs;
 use std::error::Error;
-use std::path::PathBuf;
 
 pub fn get_sqlite_path() -> Result<String, Box<dyn Error>> {
     let home_dir = match dirs::config_dir() {
@@ -18,4 +18,37 @@ pub fn get_sqlite_path() -> Result<String, Box<dyn Error>> {
     let db_path_str = db_path.to_string_lossy().into_owned();
     
     Ok(db_path_str)
-}
\ No newline at end of file
+}
+/// Binds to the stream, creating it, or updating its subjects and, if set, duplicate window
+/// when the configuration changed since it was created. Everything else an operator set on
+/// an existing stream, such as its limits, retention, storage or replicas, is kept.
+pub async fn ensure_stream(js: &jetstream::Context, config: jetstream::stream::Config) -> anyhow::Result<Stream> {
+    match js.get_stream(&config.name).await {
+        Ok(s) => {
+            println!("✅ Stream {} found", config.name);
+            let mut updated = s.cached_info().config.clone();
+            if updated.subjects != config.subjects {
+                updated.subjects = config.subjects.clone();
+                println!("✅ Updating subjects of stream {} to {:?}", config.name, config.subjects);
+            }
+            if !config.duplicate_window.is_zero() && updated.duplicate_window != config.duplicate_window {
+                updated.duplicate_window = config.duplicate_window;
+                println!("✅ Updating duplicate window of stream {} to {:?}", config.name, config.duplicate_window);
+            }
+            if updated != s.cached_info().config {
+                js.update_stream(&updated).await?;
+            }
+            Ok(s)
+        }
+        Err(_e) => match js.create_stream(config.clone()).await {
+            Ok(s) => {
+                println!("✅ Successfully created stream {}", config.name);
+                Ok(s)
+            }
+            Err(e) => {
+                println!("❌ Failed to create stream {}: {:?}", config.name, e);
+                Err(e.into())
+            }
+        },
+    }
+}
@@ -2,22 +2,38 @@
 use actix_web::{web, HttpResponse, Responder};
 use askama::Template;
 use sled::Db;
-use std::{str::FromStr, sync::Arc};
+use std::sync::Arc;
 use aws_credential_types::Credentials as AwsCredentials; // Alias to avoid conflict
 use aws_types::region::Region;
 use aws_config::BehaviorVersion; // Added import for BehaviorVersion
 
-use crate::models::{LocalCredentials, Rule, Schedule};
+use crate::models::{CircuitRow, DeadLetterRow, LocalCredentials, PatternTestForm, PatternTestResult, Rule, RuleTargetRow, ScheduleForm, ScheduleRow};
 
-use croner::Cron;
+use async_nats::jetstream::{self, kv, stream::Stream};
+use async_nats::HeaderMap;
+use futures::TryStreamExt;
 use chrono::Utc;
+use nats_common::circuit::{Circuit, CircuitState};
+use nats_common::dead_letter::DeadLetter;
+use nats_common::envelope;
+use nats_common::expression::DstGapPolicy;
+use nats_common::pattern;
+use nats_common::retry_policy::RetryPolicy;
+use nats_common::rule::{self, DeliveryFormat, InvocationMode};
+use serde_json::Value;
+use nats_common::schedule::{self, CatchUpPolicy, Schedule};
+use nats_common::topology::Topology;
+use nats_common::transform::TargetInput;
+use std::collections::{BTreeMap, HashMap};
 
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct IndexTemplate {
-    pub rules: Vec<Rule>,
+    pub rules: Vec<RuleTargetRow>,
     pub lambda_arns: Vec<(String, String)>,
+    pub form: Rule,
+    pub editing_event_type: Option<String>,
 }
 
 #[derive(Template)]
@@ -25,23 +41,82 @@ pub struct IndexTemplate {
 pub struct CredentialsTemplate;
 
 pub async fn index(db: web::Data<Arc<Db>>) -> impl Responder {
+    render_index(&db, Rule::default(), None).await
+}
+
+pub async fn edit_rule_page(db: web::Data<Arc<Db>>, path: web::Path<String>) -> impl Responder {
+    let event_type = path.into_inner();
+    match rule::get_rule(&db, &event_type) {
+        Ok(Some(existing)) => render_index(&db, Rule::from_rule(&existing), Some(event_type)).await,
+        Ok(None) => HttpResponse::NotFound().body("Rule not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+async fn render_index(db: &Db, form: Rule, editing_event_type: Option<String>) -> HttpResponse {
     let mut rules = Vec::new();
-    for result in db.iter() {
-        if let Ok((key, value)) = result {
-            let event_type = String::from_utf8(key.to_vec()).unwrap_or_default();
-            let lambda_arn = String::from_utf8(value.to_vec()).unwrap_or_default();
-            if !event_type.starts_with("aws_") {
-                // Truncate lambda_arn to 40 characters for display
-                let truncated_lambda_arn = lambda_arn.chars().take(40).collect();
-                rules.push(Rule {
-                    event_type,
-                    lambda_arn: truncated_lambda_arn,
-                });
-            }
+    for rule in rule::load_rules(db) {
+        let pattern = rule.pattern.as_ref().map(|p| p.to_string());
+        let result_subject = rule.resolved_result_subject();
+        let retry_policy = describe_retry_policy(&rule.retry_policy);
+        for target in rule.targets {
+            // Truncate lambda_arn to 40 characters for display
+            let truncated_lambda_arn = target.lambda_arn.chars().take(40).collect();
+            rules.push(RuleTargetRow {
+                event_type: rule.event_type.clone(),
+                target_id: target.id,
+                lambda_arn: truncated_lambda_arn,
+                pattern: pattern.clone(),
+                invocation_mode: rule.invocation_mode,
+                reply_subject: rule.reply_subject.clone(),
+                result_subject: result_subject.clone(),
+                retry_policy: retry_policy.clone(),
+                rate_limit: rule.rate_limit,
+                delivery_format: rule.delivery_format,
+                input: match &target.input {
+                    None => "Whole event".to_string(),
+                    Some(TargetInput::Constant { .. }) => "Constant JSON".to_string(),
+                    Some(TargetInput::Path { path }) => path.clone(),
+                    Some(TargetInput::Transformer { .. }) => "Input transformer".to_string(),
+                },
+            });
         }
     }
 
-    let lambda_arns = if let (Some(ak), Some(sk), Some(rg)) = (
+    let lambda_arns = list_lambda_arns(db).await;
+
+    let template = IndexTemplate { rules, lambda_arns, form, editing_event_type };
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(template.render().unwrap())
+}
+
+// e.g. "6 retries, 60s × 4 up to 86400s, 20% jitter, max age 3600s"
+fn describe_retry_policy(policy: &RetryPolicy) -> String {
+    if !policy.retries_enabled() {
+        return "No retries".to_string();
+    }
+    let mut summary = if policy.delays_secs.is_empty() {
+        format!(
+            "{} retries, {}s × {} up to {}s",
+            policy.max_attempts, policy.base_delay_secs, policy.multiplier, policy.max_delay_secs
+        )
+    } else {
+        let delays: Vec<String> = policy.delays_secs.iter().map(|delay| format!("{}s", delay)).collect();
+        format!("{} retries after {}", policy.max_attempts, delays.join(", "))
+    };
+    if policy.jitter > 0.0 {
+        summary.push_str(&format!(", {}% jitter", (policy.jitter * 100.0).round()));
+    }
+    if let Some(age) = policy.max_event_age_secs {
+        summary.push_str(&format!(", max age {}s", age));
+    }
+    summary
+}
+
+// Lists the Lambda functions visible with the stored AWS credentials as (arn, truncated arn) pairs
+async fn list_lambda_arns(db: &Db) -> Vec<(String, String)> {
+    if let (Some(ak), Some(sk), Some(rg)) = (
         db.get("aws_access_key").ok().flatten(),
         db.get("aws_secret_key").ok().flatten(),
         db.get("aws_region").ok().flatten(),
@@ -80,17 +155,280 @@ pub async fn index(db: web::Data<Arc<Db>>) -> impl Responder {
         }
     } else {
         vec![]
+    }
+}
+
+pub async fn create_rule(db: web::Data<Arc<Db>>, form: web::Form<Rule>) -> impl Responder {
+    let form = form.into_inner();
+    if form.lambda_arn.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Lambda function is required");
+    }
+    let parsed = parse_pattern(&form.pattern).and_then(|p| {
+        let input = parse_target_input(&form)?;
+        let settings = RuleSettings::parse(&form)?;
+        Ok((p, input, settings))
+    });
+    let (pattern, input, settings) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let event_type = form.event_type.trim();
+    if let Err(e) = rule::validate_event_type(event_type) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let is_new = match rule::get_rule(&db, event_type) {
+        Ok(existing) => existing.is_none(),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    // Adds a target, so a second rule for the same event type fans out instead of overwriting.
+    // The settings of an existing rule are kept; they are changed on its edit page
+    let mut updated = match rule::add_target(&db, event_type, form.lambda_arn.trim(), pattern, input) {
+        Ok(updated) => updated,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
     };
+    if is_new {
+        settings.apply(&mut updated);
+    }
+    if let Err(e) = rule::save_rule(&db, &updated) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    HttpResponse::SeeOther()
+        .append_header(("Location", "/?success=rule-created"))
+        .finish()
+}
 
-    let template = IndexTemplate { rules, lambda_arns };
-    HttpResponse::Ok()
-        .content_type("text/html")
-        .body(template.render().unwrap())
+// Changes the settings shared by every target of the rule; its targets are kept
+pub async fn update_rule(db: web::Data<Arc<Db>>, path: web::Path<String>, form: web::Form<Rule>) -> impl Responder {
+    let event_type = path.into_inner();
+    let form = form.into_inner();
+    let mut existing = match rule::get_rule(&db, &event_type) {
+        Ok(Some(existing)) => existing,
+        Ok(None) => return HttpResponse::NotFound().body("Rule not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let parsed = parse_pattern(&form.pattern).and_then(|p| Ok((p, RuleSettings::parse(&form)?)));
+    let (pattern, settings) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    existing.pattern = pattern;
+    settings.apply(&mut existing);
+    if let Err(e) = rule::save_rule(&db, &existing) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    HttpResponse::SeeOther()
+        .append_header(("Location", "/?success=rule-updated"))
+        .finish()
 }
-pub async fn create_rule(db: web::Data<Arc<Db>>, form: web::Form<Rule>) -> impl Responder {
-    let rule = form.into_inner();
-    db.insert(rule.event_type.as_bytes(), rule.lambda_arn.as_bytes()).unwrap();
-    HttpResponse::SeeOther().append_header(("Location", "/")).finish()
+
+// Settings of a rule that apply to all of its targets
+struct RuleSettings {
+    invocation_mode: InvocationMode,
+    reply_subject: Option<String>,
+    result_subject: Option<String>,
+    retry_policy: RetryPolicy,
+    rate_limit: Option<f64>,
+    delivery_format: DeliveryFormat,
+}
+
+impl RuleSettings {
+    fn parse(form: &Rule) -> Result<Self, String> {
+        if form.result_subject.contains(char::is_whitespace) || form.reply_subject.contains(char::is_whitespace) {
+            return Err("Subjects must not contain spaces".to_string());
+        }
+        Ok(RuleSettings {
+            invocation_mode: form.invocation_mode,
+            reply_subject: Some(form.reply_subject.trim().to_string())
+                .filter(|subject| !subject.is_empty() && form.invocation_mode == InvocationMode::RequestResponse),
+            result_subject: Some(form.result_subject.trim().to_string()).filter(|subject| !subject.is_empty()),
+            retry_policy: parse_retry_policy(form)?,
+            rate_limit: parse_rate_limit(&form.rate_limit)?,
+            delivery_format: form.delivery_format,
+        })
+    }
+
+    fn apply(self, rule: &mut rule::Rule) {
+        rule.invocation_mode = self.invocation_mode;
+        rule.reply_subject = self.reply_subject;
+        rule.result_subject = self.result_subject;
+        rule.retry_policy = self.retry_policy;
+        rule.rate_limit = self.rate_limit;
+        rule.delivery_format = self.delivery_format;
+    }
+}
+
+impl Rule {
+    // The form for editing the rule's settings, as saved
+    fn from_rule(rule: &rule::Rule) -> Self {
+        let policy = &rule.retry_policy;
+        let backoff = |value: String| if policy.delays_secs.is_empty() { value } else { String::new() };
+        Rule {
+            event_type: rule.event_type.clone(),
+            pattern: rule.pattern.as_ref().map(|p| p.to_string()).unwrap_or_default(),
+            invocation_mode: rule.invocation_mode,
+            reply_subject: rule.reply_subject.clone().unwrap_or_default(),
+            result_subject: rule.result_subject.clone().unwrap_or_default(),
+            retry_max_attempts: policy.max_attempts.to_string(),
+            retry_max_event_age: policy.max_event_age_secs.map(|age| age.to_string()).unwrap_or_default(),
+            retry_base_delay: backoff(policy.base_delay_secs.to_string()),
+            retry_multiplier: backoff(policy.multiplier.to_string()),
+            retry_max_delay: backoff(policy.max_delay_secs.to_string()),
+            retry_jitter: if policy.jitter > 0.0 { policy.jitter.to_string() } else { String::new() },
+            rate_limit: rule.rate_limit.map(|rate| rate.to_string()).unwrap_or_default(),
+            delivery_format: rule.delivery_format,
+            ..Rule::default()
+        }
+    }
+}
+
+// Parses and validates an optional event pattern field; an empty field means no pattern
+fn parse_pattern(input: &str) -> Result<Option<Value>, String> {
+    if input.trim().is_empty() {
+        return Ok(None);
+    }
+    let pattern: Value = serde_json::from_str(input).map_err(|e| format!("Pattern is not valid JSON: {}", e))?;
+    pattern::validate_pattern(&pattern).map_err(|e| format!("Invalid event pattern: {}", e))?;
+    Ok(Some(pattern))
+}
+
+// Builds the target input from the rule form; no mode means the whole event is sent
+fn parse_target_input(form: &Rule) -> Result<Option<TargetInput>, String> {
+    let input = match form.input_mode.as_str() {
+        "" => return Ok(None),
+        "constant" => TargetInput::Constant {
+            json: serde_json::from_str(&form.input_json)
+                .map_err(|e| format!("Constant input is not valid JSON: {}", e))?,
+        },
+        "path" => TargetInput::Path {
+            path: form.input_path.trim().to_string(),
+        },
+        "transformer" => TargetInput::Transformer {
+            paths_map: serde_json::from_str::<BTreeMap<String, String>>(&form.input_paths_map)
+                .map_err(|e| format!("Input paths map must be a JSON object of names to paths: {}", e))?,
+            template: form.input_template.clone(),
+        },
+        other => return Err(format!("Unknown input mode '{}'", other)),
+    };
+    input.validate()?;
+    Ok(Some(input))
+}
+
+// Builds the rule's retry policy from the form, using the default for empty fields. Without
+// any backoff field the default fixed delays are kept
+fn parse_retry_policy(form: &Rule) -> Result<RetryPolicy, String> {
+    fn field<T: std::str::FromStr>(value: &str, default: T, name: &str) -> Result<T, String> {
+        if value.trim().is_empty() {
+            return Ok(default);
+        }
+        value.trim().parse().map_err(|_| format!("{} must be a number", name))
+    }
+
+    let default = RetryPolicy::default();
+    let uses_backoff = [&form.retry_base_delay, &form.retry_multiplier, &form.retry_max_delay]
+        .iter()
+        .any(|field| !field.trim().is_empty());
+    let policy = RetryPolicy {
+        max_attempts: field(&form.retry_max_attempts, default.max_attempts, "Max retry attempts")?,
+        max_event_age_secs: match form.retry_max_event_age.trim() {
+            "" => None,
+            age => Some(field(age, 0, "Max event age")?),
+        },
+        base_delay_secs: field(&form.retry_base_delay, default.base_delay_secs, "Base delay")?,
+        multiplier: field(&form.retry_multiplier, default.multiplier, "Multiplier")?,
+        max_delay_secs: field(&form.retry_max_delay, default.max_delay_secs, "Max delay")?,
+        jitter: field(&form.retry_jitter, default.jitter, "Jitter")?,
+        delays_secs: if uses_backoff { Vec::new() } else { default.delays_secs },
+    };
+    policy.validate()?;
+    Ok(policy)
+}
+
+// Parses the optional invocations-per-second limit; an empty field means no limit
+fn parse_rate_limit(input: &str) -> Result<Option<f64>, String> {
+    if input.trim().is_empty() {
+        return Ok(None);
+    }
+    match input.trim().parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(Some(rate)),
+        _ => Err("Rate limit must be a positive number of invocations per second".to_string()),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "pattern_tester.html")]
+pub struct PatternTesterTemplate {
+    pub form: PatternTestForm,
+    pub results: Vec<PatternTestResult>,
+    pub draft_matches: Option<bool>,
+    pub error: Option<String>,
+}
+
+pub async fn pattern_tester_page() -> impl Responder {
+    let template = PatternTesterTemplate {
+        form: PatternTestForm::default(),
+        results: Vec::new(),
+        draft_matches: None,
+        error: None,
+    };
+    HttpResponse::Ok().content_type("text/html").body(template.render().unwrap())
+}
+
+// Runs a sample event through every saved rule, plus the draft pattern if one was given
+pub async fn test_pattern(db: web::Data<Arc<Db>>, form: web::Form<PatternTestForm>) -> impl Responder {
+    let form = form.into_inner();
+    let parsed = serde_json::from_str::<Value>(&form.event)
+        .map_err(|e| format!("Event is not valid JSON: {}", e))
+        .and_then(|event| parse_pattern(&form.pattern).map(|draft| (event, draft)));
+
+    let (event, draft) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let template = PatternTesterTemplate {
+                form,
+                results: Vec::new(),
+                draft_matches: None,
+                error: Some(e),
+            };
+            return HttpResponse::BadRequest().content_type("text/html").body(template.render().unwrap());
+        }
+    };
+
+    let results = rule::load_rules(&db)
+        .into_iter()
+        .map(|rule| PatternTestResult {
+            pattern: match &rule.pattern {
+                Some(pattern) => serde_json::to_string_pretty(pattern).unwrap_or_default(),
+                None => format!("event_type = \"{}\"", rule.event_type),
+            },
+            matched: rule.matches(&event),
+            rule: rule.event_type,
+        })
+        .collect();
+
+    let template = PatternTesterTemplate {
+        draft_matches: draft.map(|draft| pattern::matches(&draft, &event)),
+        form,
+        results,
+        error: None,
+    };
+    HttpResponse::Ok().content_type("text/html").body(template.render().unwrap())
+}
+
+pub async fn delete_rule(db: web::Data<Arc<Db>>, path: web::Path<String>) -> impl Responder {
+    match rule::delete_rule(&db, &path.into_inner()) {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Rule not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+pub async fn delete_rule_target(db: web::Data<Arc<Db>>, path: web::Path<(String, String)>) -> impl Responder {
+    let (event_type, target_id) = path.into_inner();
+    match rule::remove_target(&db, &event_type, &target_id) {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Target not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
 }
 
 pub async fn credentials_page() -> impl Responder {
@@ -110,84 +448,491 @@ pub async fn set_credentials(db: web::Data<Arc<Db>>, form: web::Form<LocalCreden
 
 
 
+
 #[derive(Template)]
 #[template(path = "scheduler.html")]
 pub struct SchedulerTemplate {
-    pub schedules: Vec<Schedule>,
+    pub schedules: Vec<ScheduleRow>,
     pub lambda_arns: Vec<(String, String)>,
+    pub form: ScheduleForm,
+    pub editing_id: Option<String>,
+    pub error: Option<String>,
 }
 
+impl ScheduleForm {
+    fn from_schedule(schedule: &Schedule) -> Self {
+        ScheduleForm {
+            event_type: schedule.event_type.clone(),
+            lambda_arn: schedule.lambda_arn.clone(),
+            schedule: schedule.expression.clone(),
+            payload: schedule
+                .payload
+                .as_ref()
+                .map(|p| p.to_string())
+                .unwrap_or_default(),
+            timezone: schedule.timezone.clone(),
+            dst_gap: schedule.dst_gap,
+            catch_up: match schedule.catch_up {
+                CatchUpPolicy::Skip => "skip",
+                CatchUpPolicy::FireOnce => "fire_once",
+                CatchUpPolicy::FireAll { .. } => "fire_all",
+            }
+            .to_string(),
+            catch_up_max: match schedule.catch_up {
+                CatchUpPolicy::FireAll { max_runs } => max_runs.to_string(),
+                _ => String::new(),
+            },
+            flexible_window: schedule
+                .flexible_window_minutes
+                .map(|window| window.to_string())
+                .unwrap_or_default(),
+        }
+    }
 
-pub async fn scheduler(db: web::Data<Arc<Db>>) -> impl Responder {
-    let mut schedules = Vec::new();
-    for result in db.iter() {
-        if let Ok((key, value)) = result {
-            let key_str = String::from_utf8(key.to_vec()).unwrap_or_default();
-            if key_str.starts_with("schedule:") {
-                let event_type = key_str.strip_prefix("schedule:").unwrap_or("").to_string();
-                let value_str = String::from_utf8(value.to_vec()).unwrap_or_default();
-                let parts: Vec<String> = value_str.split(':').map(|s| s.to_string()).collect();
-                if parts.len() >= 2 {
-                    let id = "1".to_string();
-                    let lambda_arn = parts[0].clone();
-                    let cron = parts[1].clone();
-                    let truncated_lambda_arn = lambda_arn.chars().take(40).collect::<String>();
-                    let next_trigger = Cron::from_str(&cron).expect("Couldn't parse cron string");
-                    schedules.push(Schedule {
-                        id,
-                        event_type,
-                        lambda_arn: truncated_lambda_arn,
-                        cron,
-                        next_trigger: next_trigger.to_string(),
-                    });
+    fn into_schedule(self, id: String, enabled: bool) -> Result<Schedule, String> {
+        let payload = if self.payload.trim().is_empty() {
+            None
+        } else {
+            Some(
+                serde_json::from_str(&self.payload)
+                    .map_err(|e| format!("Payload is not valid JSON: {}", e))?,
+            )
+        };
+        let catch_up = match self.catch_up.as_str() {
+            "" | "skip" => CatchUpPolicy::Skip,
+            "fire_once" => CatchUpPolicy::FireOnce,
+            "fire_all" => CatchUpPolicy::FireAll {
+                max_runs: self
+                    .catch_up_max
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .ok_or("Max missed runs must be a positive number")?,
+            },
+            other => return Err(format!("Unknown catch-up policy '{}'", other)),
+        };
+        let flexible_window_minutes = if self.flexible_window.trim().is_empty() {
+            None
+        } else {
+            Some(
+                self.flexible_window
+                    .trim()
+                    .parse()
+                    .map_err(|_| "Flexible time window must be a number of minutes")?,
+            )
+        };
+        let timezone = if self.timezone.trim().is_empty() {
+            "UTC".to_string()
+        } else {
+            self.timezone.trim().to_string()
+        };
+
+        let schedule = Schedule {
+            id,
+            event_type: self.event_type.trim().to_string(),
+            lambda_arn: self.lambda_arn.trim().to_string(),
+            expression: self.schedule.trim().to_string(),
+            payload,
+            timezone,
+            dst_gap: self.dst_gap,
+            catch_up,
+            flexible_window_minutes,
+            enabled,
+            updated_at: Some(Utc::now().to_rfc3339()),
+        };
+        schedule.validate()?;
+        Ok(schedule)
+    }
+}
+
+async fn render_scheduler(
+    db: &Db,
+    form: ScheduleForm,
+    editing_id: Option<String>,
+    error: Option<String>,
+) -> HttpResponse {
+    let now = Utc::now();
+    let schedules = schedule::load_schedules(db)
+        .into_iter()
+        .map(|s| {
+            let (next_trigger, next_trigger_utc) = if !s.enabled {
+                ("Paused".to_string(), String::new())
+            } else {
+                match (s.next_fire(now), s.tz()) {
+                    (Ok(Some(next)), Ok(tz)) => (
+                        next.with_timezone(&tz).format("%Y-%m-%d %H:%M:%S %Z").to_string(),
+                        next.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+                    ),
+                    (Ok(None), _) => ("No upcoming runs".to_string(), String::new()),
+                    (Err(e), _) | (_, Err(e)) => (e, String::new()),
                 }
+            };
+            ScheduleRow {
+                id: s.id,
+                event_type: s.event_type,
+                lambda_arn: s.lambda_arn.chars().take(40).collect(),
+                expression: s.expression,
+                timezone: s.timezone,
+                enabled: s.enabled,
+                next_trigger,
+                next_trigger_utc,
             }
+        })
+        .collect();
+
+    let lambda_arns = list_lambda_arns(db).await;
+
+    let template = SchedulerTemplate { schedules, lambda_arns, form, editing_id, error: error.clone() };
+    let mut response = if error.is_some() {
+        HttpResponse::BadRequest()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .content_type("text/html")
+        .body(template.render().unwrap())
+}
+
+pub async fn scheduler(db: web::Data<Arc<Db>>) -> impl Responder {
+    render_scheduler(&db, ScheduleForm::default(), None, None).await
+}
+
+pub async fn edit_schedule_page(db: web::Data<Arc<Db>>, path: web::Path<String>) -> impl Responder {
+    let id = path.into_inner();
+    match schedule::get_schedule(&db, &id) {
+        Ok(Some(existing)) => {
+            render_scheduler(&db, ScheduleForm::from_schedule(&existing), Some(id), None).await
         }
+        Ok(None) => HttpResponse::NotFound().body("Schedule not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
+}
 
-    let lambda_arns = if let (Some(ak), Some(sk), Some(rg)) = (
-        db.get("aws_access_key").ok().flatten(),
-        db.get("aws_secret_key").ok().flatten(),
-        db.get("aws_region").ok().flatten(),
-    ) {
-        let access_key = String::from_utf8(ak.to_vec()).unwrap_or_default();
-        let secret_key = String::from_utf8(sk.to_vec()).unwrap_or_default();
-        let region = String::from_utf8(rg.to_vec()).unwrap_or_default();
-        if !access_key.is_empty() && !secret_key.is_empty() && !region.is_empty() {
-            let credentials = AwsCredentials::new(access_key, secret_key, None, None, "static");
-            let config = aws_config::ConfigLoader::default()
-                .credentials_provider(credentials)
-                .region(Region::new(region))
-                .behavior_version(BehaviorVersion::latest())
-                .load()
-                .await;
-            let client = aws_sdk_lambda::Client::new(&config);
-            match client.list_functions().send().await {
-                Ok(output) => output
-                    .functions
-                    .unwrap_or_default()
-                    .into_iter()
-                    .filter_map(|f| {
-                        f.function_arn.map(|arn| {
-                            let truncated = arn.chars().take(50).collect::<String>();
-                            (arn, truncated)
-                        })
-                    })
-                    .collect(),
-                Err(e) => {
-                    eprintln!("Error fetching Lambdas: {:?}", e);
-                    vec![]
+pub async fn create_schedule(db: web::Data<Arc<Db>>, form: web::Form<ScheduleForm>) -> impl Responder {
+    let form = form.into_inner();
+    let id = match schedule::new_schedule_id(&db) {
+        Ok(id) => id,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    match form.clone().into_schedule(id, true) {
+        Ok(new_schedule) => {
+            schedule::save_schedule(&db, &new_schedule).unwrap();
+            HttpResponse::SeeOther()
+                .append_header(("Location", "/scheduler?success=schedule-created"))
+                .finish()
+        }
+        Err(e) => render_scheduler(&db, form, None, Some(e)).await,
+    }
+}
+
+pub async fn update_schedule(
+    db: web::Data<Arc<Db>>,
+    path: web::Path<String>,
+    form: web::Form<ScheduleForm>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let form = form.into_inner();
+    let existing = match schedule::get_schedule(&db, &id) {
+        Ok(Some(existing)) => existing,
+        Ok(None) => return HttpResponse::NotFound().body("Schedule not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    match form.clone().into_schedule(id.clone(), existing.enabled) {
+        Ok(updated) => {
+            schedule::save_schedule(&db, &updated).unwrap();
+            HttpResponse::SeeOther()
+                .append_header(("Location", "/scheduler?success=schedule-updated"))
+                .finish()
+        }
+        Err(e) => render_scheduler(&db, form, Some(id), Some(e)).await,
+    }
+}
+
+pub async fn delete_schedule(db: web::Data<Arc<Db>>, path: web::Path<String>) -> impl Responder {
+    match schedule::delete_schedule(&db, &path.into_inner()) {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Schedule not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+pub async fn pause_schedule(db: web::Data<Arc<Db>>, path: web::Path<String>) -> impl Responder {
+    set_schedule_enabled(&db, &path.into_inner(), false)
+}
+
+pub async fn resume_schedule(db: web::Data<Arc<Db>>, path: web::Path<String>) -> impl Responder {
+    set_schedule_enabled(&db, &path.into_inner(), true)
+}
+
+fn set_schedule_enabled(db: &Db, id: &str, enabled: bool) -> HttpResponse {
+    match schedule::get_schedule(db, id) {
+        Ok(Some(mut existing)) => {
+            existing.enabled = enabled;
+            // Runs due while the schedule was paused are not caught up on resume
+            existing.updated_at = Some(Utc::now().to_rfc3339());
+            schedule::save_schedule(db, &existing).unwrap();
+            HttpResponse::Ok().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().body("Schedule not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+
+// Most recent entries shown on the dead letters page
+const DEAD_LETTER_PAGE_SIZE: usize = 100;
+// Most sequences read to fill a page when redrives and purges left gaps
+const MAX_DEAD_LETTER_SCAN: u64 = 10_000;
+
+#[derive(Template)]
+#[template(path = "dead_letters.html")]
+pub struct DeadLettersTemplate {
+    pub dead_letters: Vec<DeadLetterRow>,
+    pub total: u64,
+    pub selected: Option<(DeadLetterRow, String)>, // Entry being inspected, with its pretty-printed record
+    pub error: Option<String>,
+}
+
+// The dead-letter stream only exists once nats_consumer has started
+async fn dead_letter_stream(js: &jetstream::Context, topology: &Topology) -> Option<Stream> {
+    js.get_stream(&topology.dead_letter_stream).await.ok()
+}
+
+async fn get_dead_letter(stream: &Stream, sequence: u64) -> Option<DeadLetterRow> {
+    let message = stream.get_raw_message(sequence).await.ok()?;
+    let record: DeadLetter = serde_json::from_slice(&message.payload).ok()?;
+    Some(DeadLetterRow { sequence, record })
+}
+
+/// The newest dead letters, newest first. Each window of sequences before the last one is
+/// read in a single batch, and the window grows while gaps leave it short of a page.
+async fn newest_dead_letters(stream: &Stream, state: &jetstream::stream::State) -> Result<Vec<DeadLetterRow>, String> {
+    let mut window = DEAD_LETTER_PAGE_SIZE as u64;
+    loop {
+        let start = state.last_sequence.saturating_sub(window - 1).max(state.first_sequence).max(1);
+        let mut rows = read_dead_letters(stream, start, window).await?;
+        if rows.len() >= DEAD_LETTER_PAGE_SIZE || start <= state.first_sequence || window >= MAX_DEAD_LETTER_SCAN {
+            rows.reverse();
+            rows.truncate(DEAD_LETTER_PAGE_SIZE);
+            return Ok(rows);
+        }
+        window = (window * 4).min(MAX_DEAD_LETTER_SCAN);
+    }
+}
+
+// Up to `max` entries from sequence `start` on, read by a short-lived consumer
+async fn read_dead_letters(stream: &Stream, start: u64, max: u64) -> Result<Vec<DeadLetterRow>, String> {
+    let consumer = stream
+        .create_consumer(jetstream::consumer::pull::Config {
+            deliver_policy: jetstream::consumer::DeliverPolicy::ByStartSequence { start_sequence: start },
+            ack_policy: jetstream::consumer::AckPolicy::None,
+            inactive_threshold: std::time::Duration::from_secs(5),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut messages = consumer
+        .fetch()
+        .max_messages(max as usize)
+        .messages()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut rows = Vec::new();
+    while let Some(message) = messages.try_next().await.map_err(|e| e.to_string())? {
+        let sequence = message.info().map_err(|e| e.to_string())?.stream_sequence;
+        if let Ok(record) = serde_json::from_slice(&message.payload) {
+            rows.push(DeadLetterRow { sequence, record });
+        }
+    }
+    let _ = stream.delete_consumer(&consumer.cached_info().name).await;
+    Ok(rows)
+}
+
+async fn render_dead_letters(js: &jetstream::Context, topology: &Topology, selected: Option<u64>) -> HttpResponse {
+    let mut template = DeadLettersTemplate {
+        dead_letters: Vec::new(),
+        total: 0,
+        selected: None,
+        error: None,
+    };
+
+    match dead_letter_stream(js, topology).await {
+        Some(mut stream) => match stream.info().await.map(|info| info.state.clone()) {
+            Ok(state) => {
+                template.total = state.messages;
+                if state.messages > 0 {
+                    match newest_dead_letters(&stream, &state).await {
+                        Ok(rows) => template.dead_letters = rows,
+                        Err(e) => template.error = Some(format!("Failed to read the dead-letter stream: {}", e)),
+                    }
+                }
+                if let Some(sequence) = selected {
+                    match get_dead_letter(&stream, sequence).await {
+                        Some(row) => {
+                            let pretty = serde_json::to_string_pretty(&row.record).unwrap_or_default();
+                            template.selected = Some((row, pretty));
+                        }
+                        None => template.error = Some(format!("Dead letter {} not found", sequence)),
+                    }
                 }
             }
-        } else {
-            vec![]
+            Err(e) => template.error = Some(format!("Failed to read the dead-letter stream: {}", e)),
+        },
+        None => template.error = Some("The dead-letter stream does not exist yet or NATS is unreachable".to_string()),
+    }
+
+    HttpResponse::Ok().content_type("text/html").body(template.render().unwrap())
+}
+
+pub async fn dead_letters(js: web::Data<jetstream::Context>, topology: web::Data<Topology>) -> impl Responder {
+    render_dead_letters(&js, &topology, None).await
+}
+
+pub async fn inspect_dead_letter(
+    js: web::Data<jetstream::Context>,
+    topology: web::Data<Topology>,
+    path: web::Path<u64>,
+) -> impl Responder {
+    render_dead_letters(&js, &topology, Some(path.into_inner())).await
+}
+
+// Publishes the original event again for the failed target only, then removes the entry
+pub async fn redrive_dead_letter(
+    js: web::Data<jetstream::Context>,
+    topology: web::Data<Topology>,
+    path: web::Path<u64>,
+) -> impl Responder {
+    let sequence = path.into_inner();
+    let Some(stream) = dead_letter_stream(&js, &topology).await else {
+        return HttpResponse::ServiceUnavailable().body("Dead-letter stream unavailable");
+    };
+    let Some(row) = get_dead_letter(&stream, sequence).await else {
+        return HttpResponse::NotFound().body("Dead letter not found");
+    };
+
+    // The entry is deleted only once the event stream stored the redriven event. The message id
+    // is derived from the entry, so redriving it again after a lost ack is deduplicated
+    let event = serde_json::to_vec(&row.record.redrive_event()).unwrap();
+    let mut headers = HeaderMap::new();
+    let msg_id = envelope::republish_msg_id(&row.record.event_id, &row.record.target_id, &format!("redrive-{}", sequence));
+    headers.insert(envelope::MSG_ID_HEADER, msg_id.as_str());
+    let stored = match js.publish_with_headers(topology.event_subject.clone(), headers, event.into()).await {
+        Ok(ack) => ack.await.map(|_| ()).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = stored {
+        return HttpResponse::InternalServerError().body(e);
+    }
+    match stream.delete_message(sequence).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+pub async fn delete_dead_letter(
+    js: web::Data<jetstream::Context>,
+    topology: web::Data<Topology>,
+    path: web::Path<u64>,
+) -> impl Responder {
+    let Some(stream) = dead_letter_stream(&js, &topology).await else {
+        return HttpResponse::ServiceUnavailable().body("Dead-letter stream unavailable");
+    };
+    match stream.delete_message(path.into_inner()).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::NotFound().body(e.to_string()),
+    }
+}
+
+pub async fn purge_dead_letters(js: web::Data<jetstream::Context>, topology: web::Data<Topology>) -> impl Responder {
+    let Some(stream) = dead_letter_stream(&js, &topology).await else {
+        return HttpResponse::ServiceUnavailable().body("Dead-letter stream unavailable");
+    };
+    match stream.purge().await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "circuits.html")]
+pub struct CircuitsTemplate {
+    pub circuits: Vec<CircuitRow>,
+    pub error: Option<String>,
+}
+
+// The circuit bucket only exists once nats_consumer has started
+async fn circuit_store(js: &jetstream::Context, topology: &Topology) -> Option<kv::Store> {
+    js.get_key_value(&topology.circuit_bucket).await.ok()
+}
+
+// Functions that failed since their last success, with the events parked for each
+async fn load_circuits(js: &jetstream::Context, topology: &Topology, store: &kv::Store) -> Result<Vec<CircuitRow>, String> {
+    let mut held = HashMap::new();
+    if let Ok(stream) = js.get_stream(&topology.holding_stream).await {
+        let mut subjects = stream
+            .info_with_subjects(format!("{}>", topology.holding_subject_prefix))
+            .await
+            .map_err(|e| e.to_string())?;
+        while let Some((subject, count)) = subjects.try_next().await.map_err(|e| e.to_string())? {
+            held.insert(subject, count);
         }
-    } else {
-        vec![]
+    }
+
+    let mut rows = Vec::new();
+    let mut keys = store.keys().await.map_err(|e| e.to_string())?;
+    while let Some(key) = keys.try_next().await.map_err(|e| e.to_string())? {
+        let Some(value) = store.get(&key).await.map_err(|e| e.to_string())? else {
+            continue;
+        };
+        let Ok(circuit) = serde_json::from_slice::<Circuit>(&value) else {
+            continue;
+        };
+        rows.push(CircuitRow {
+            key,
+            state: match circuit.state {
+                CircuitState::Closed => "Closed",
+                CircuitState::Open => "Open",
+                CircuitState::HalfOpen => "Half-open",
+            }
+            .to_string(),
+            held: held.get(&topology.holding_subject(&circuit.lambda_arn)).copied().unwrap_or(0),
+            circuit,
+        });
+    }
+    rows.sort_by(|a, b| a.circuit.lambda_arn.cmp(&b.circuit.lambda_arn));
+    Ok(rows)
+}
+
+pub async fn circuits(js: web::Data<jetstream::Context>, topology: web::Data<Topology>) -> impl Responder {
+    let mut template = CircuitsTemplate {
+        circuits: Vec::new(),
+        error: None,
     };
+    match circuit_store(&js, &topology).await {
+        Some(store) => match load_circuits(&js, &topology, &store).await {
+            Ok(circuits) => template.circuits = circuits,
+            Err(e) => template.error = Some(format!("Failed to read the circuits: {}", e)),
+        },
+        None => template.error = Some("The circuit bucket does not exist yet or NATS is unreachable".to_string()),
+    }
+    HttpResponse::Ok().content_type("text/html").body(template.render().unwrap())
+}
 
-    let template = SchedulerTemplate { schedules, lambda_arns };
-    HttpResponse::Ok()
-        .content_type("text/html")
-        .body(template.render().unwrap())
-}
\ No newline at end of file
+// Closes the circuit; nats_consumer then releases its parked events to the event subject
+pub async fn reset_circuit(
+    js: web::Data<jetstream::Context>,
+    topology: web::Data<Topology>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let Some(store) = circuit_store(&js, &topology).await else {
+        return HttpResponse::ServiceUnavailable().body("Circuit bucket unavailable");
+    };
+    match store.delete(path.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
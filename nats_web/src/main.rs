@@ -1,7 +1,7 @@
 // src/main.rs
 use actix_web::{web, App, HttpServer};
 use actix_web::middleware::Logger;
-use sled::Db;
+use nats_common::topology::Topology;
 use std::sync::Arc;
 
 mod handlers;
@@ -10,17 +10,53 @@ mod utils;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let db_path = utils::get_sqlite_path().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let db_path = utils::get_sqlite_path().map_err(|e| std::io::Error::other(e.to_string()))?;
     let db = Arc::new(sled::open(db_path).unwrap());
+    let migrated = nats_common::rule::migrate_legacy_rules(&db).map_err(std::io::Error::other)?;
+    if migrated > 0 {
+        println!("✅ Migrated {} legacy rules", migrated);
+    }
+    // Same topology as nats_consumer, so redrives go to its event subject
+    let topology = Topology::load().map_err(std::io::Error::other)?;
+
+    // NATS is only needed for the dead letters and circuits pages, so the UI starts even if it is down
+    let nats_url = std::env::var("NATS_URL").unwrap_or_else(|_| "nats://localhost:4222".to_string());
+    let nats_client = async_nats::ConnectOptions::new()
+        .retry_on_initial_connect()
+        .connect(&nats_url)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let js = async_nats::jetstream::new(nats_client);
 
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .app_data(web::Data::new(db.clone()))
+            .app_data(web::Data::new(js.clone()))
+            .app_data(web::Data::new(topology.clone()))
             .route("/", web::get().to(handlers::index))
             .route("/rule", web::post().to(handlers::create_rule))
+            .route("/edit-rule/{event_type}", web::get().to(handlers::edit_rule_page))
+            .route("/update-rule/{event_type}", web::post().to(handlers::update_rule))
+            .route("/delete-rule/{event_type}", web::delete().to(handlers::delete_rule))
+            .route("/delete-rule/{event_type}/{target_id}", web::delete().to(handlers::delete_rule_target))
+            .route("/pattern-tester", web::get().to(handlers::pattern_tester_page))
+            .route("/pattern-tester", web::post().to(handlers::test_pattern))
             .route("/credentials", web::get().to(handlers::credentials_page))
             .route("/scheduler", web::get().to(handlers::scheduler))
+            .route("/create-schedule", web::post().to(handlers::create_schedule))
+            .route("/edit-schedule/{id}", web::get().to(handlers::edit_schedule_page))
+            .route("/update-schedule/{id}", web::post().to(handlers::update_schedule))
+            .route("/delete-schedule/{id}", web::delete().to(handlers::delete_schedule))
+            .route("/pause-schedule/{id}", web::post().to(handlers::pause_schedule))
+            .route("/resume-schedule/{id}", web::post().to(handlers::resume_schedule))
+            .route("/dead-letters", web::get().to(handlers::dead_letters))
+            .route("/dead-letter/{sequence}", web::get().to(handlers::inspect_dead_letter))
+            .route("/redrive-dead-letter/{sequence}", web::post().to(handlers::redrive_dead_letter))
+            .route("/delete-dead-letter/{sequence}", web::delete().to(handlers::delete_dead_letter))
+            .route("/purge-dead-letters", web::post().to(handlers::purge_dead_letters))
+            .route("/circuits", web::get().to(handlers::circuits))
+            .route("/reset-circuit/{key}", web::post().to(handlers::reset_circuit))
             .route("/set_credentials", web::post().to(handlers::set_credentials))
     })
     .bind(("127.0.0.1", 8082))?
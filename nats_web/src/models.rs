@@ -1,9 +1,80 @@
+use nats_common::circuit::Circuit;
+use nats_common::dead_letter::DeadLetter;
+use nats_common::expression::DstGapPolicy;
+use nats_common::rule::{DeliveryFormat, InvocationMode};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Rule {
     pub event_type: String,
-    pub lambda_arn: String,
+    #[serde(default)]
+    pub lambda_arn: String,      // Not on the edit form, which leaves the targets alone
+    #[serde(default)]
+    pub pattern: String,         // Optional EventBridge event pattern (JSON)
+    #[serde(default)]
+    pub input_mode: String,      // "", "constant", "path" or "transformer"
+    #[serde(default)]
+    pub input_json: String,      // Constant input (JSON)
+    #[serde(default)]
+    pub input_path: String,      // JSONPath selecting the input, e.g. "$.detail"
+    #[serde(default)]
+    pub input_paths_map: String, // Transformer paths map (JSON object of name -> JSONPath)
+    #[serde(default)]
+    pub input_template: String,  // Transformer template with <name> placeholders
+    #[serde(default)]
+    pub invocation_mode: InvocationMode,
+    #[serde(default)]
+    pub reply_subject: String,   // RequestResponse only: where the function's response is published
+    #[serde(default)]
+    pub result_subject: String,  // Where completion events are published, e.g. "lambda.result.{event_type}"
+    // Retry policy; empty fields take the default
+    #[serde(default)]
+    pub retry_max_attempts: String, // 0 disables retries
+    #[serde(default)]
+    pub retry_max_event_age: String, // Seconds, empty for no limit
+    #[serde(default)]
+    pub retry_base_delay: String,   // Seconds
+    #[serde(default)]
+    pub retry_multiplier: String,
+    #[serde(default)]
+    pub retry_max_delay: String,    // Seconds
+    #[serde(default)]
+    pub retry_jitter: String,       // Fraction from 0 to 1
+    #[serde(default)]
+    pub rate_limit: String,         // Invocations per second of each target, empty for no limit
+    #[serde(default)]
+    pub delivery_format: DeliveryFormat,
+}
+
+// One target of a rule, as listed on the index page
+#[derive(Debug, Clone)]
+pub struct RuleTargetRow {
+    pub event_type: String,
+    pub target_id: String,
+    pub lambda_arn: String,      // Truncated for display in handler
+    pub pattern: Option<String>, // Event pattern as JSON, None when the rule matches on event_type
+    pub input: String,           // Summary of the target's input transformation
+    pub invocation_mode: InvocationMode,
+    pub reply_subject: Option<String>,
+    pub result_subject: Option<String>,
+    pub retry_policy: String,    // Summary of the rule's retry policy
+    pub rate_limit: Option<f64>, // Invocations per second
+    pub delivery_format: DeliveryFormat,
+}
+
+// Form fields posted by the pattern tester page
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatternTestForm {
+    pub event: String,
+    #[serde(default)]
+    pub pattern: String,         // Draft pattern tested alongside the saved rules
+}
+
+#[derive(Debug, Clone)]
+pub struct PatternTestResult {
+    pub rule: String,
+    pub pattern: String,         // Pretty-printed pattern, or the event_type match for rules without one
+    pub matched: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,10 +86,49 @@ pub struct LocalCredentials {
 
 
 #[derive(Debug, Clone)]
-pub struct Schedule {
-    pub id: String,  
+pub struct ScheduleRow {
+    pub id: String,
     pub event_type: String,      // Unique identifier for the scheduled event (e.g., "daily.backup")
     pub lambda_arn: String,      // ARN of the Lambda function to trigger (truncated for display in handler)
-    pub cron: String,            // Cron expression defining the schedule (e.g., "0 9 * * ?")
-    pub next_trigger: String,    // Calculated next trigger time (e.g., "2025-08-05 09:00:00 UTC")
-}
\ No newline at end of file
+    pub expression: String,      // Cron, rate() or at() expression (e.g., "0 9 * * *", "rate(5 minutes)")
+    pub timezone: String,        // IANA timezone the expression is evaluated in
+    pub enabled: bool,
+    pub next_trigger: String,    // Calculated next trigger time in the schedule's timezone (e.g., "2025-08-05 11:00:00 CEST")
+    pub next_trigger_utc: String, // Same instant in UTC (e.g., "2025-08-05 09:00:00 UTC")
+}
+
+// Form fields posted by the create/edit schedule form on the scheduler page
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScheduleForm {
+    pub event_type: String,
+    pub lambda_arn: String,
+    pub schedule: String,
+    #[serde(default)]
+    pub payload: String,
+    #[serde(default)]
+    pub timezone: String,
+    #[serde(default)]
+    pub dst_gap: DstGapPolicy,
+    #[serde(default)]
+    pub catch_up: String,        // "skip", "fire_once" or "fire_all"
+    #[serde(default)]
+    pub catch_up_max: String,    // Cap on missed runs fired with "fire_all"
+    #[serde(default)]
+    pub flexible_window: String, // Flexible time window in minutes, empty for none
+}
+
+// An entry of the dead-letter stream, as listed on the dead letters page
+#[derive(Debug, Clone)]
+pub struct DeadLetterRow {
+    pub sequence: u64,           // Stream sequence, identifies the entry for inspect/redrive/purge
+    pub record: DeadLetter,
+}
+
+// A function's circuit, as listed on the circuits page
+#[derive(Debug, Clone)]
+pub struct CircuitRow {
+    pub key: String,             // KV key, identifies the circuit for reset
+    pub state: String,           // "Closed", "Open" or "Half-open"
+    pub held: usize,             // Events parked in the holding stream
+    pub circuit: Circuit,
+}
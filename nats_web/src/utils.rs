@@ -1,6 +1,5 @@
 use std::fs;
 use std::error::Error;
-use std::path::PathBuf;
 
 pub fn get_sqlite_path() -> Result<String, Box<dyn Error>> {
     let home_dir = match dirs::config_dir() {